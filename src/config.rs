@@ -0,0 +1,164 @@
+//! Configuration for connecting to WhatsApp Web.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::session::SessionStore;
+use crate::errors::*;
+
+/// WhatsApp Web WebSocket endpoint URL.
+pub const ENDPOINT_URL: &str = "wss://web.whatsapp.com/ws";
+/// WhatsApp Web WebSocket origin header value.
+pub const ORIGIN_URL: &str = "https://web.whatsapp.com";
+
+/// A proxy server to connect through.
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// An HTTP proxy, used via the `CONNECT` method.
+    Http {
+        /// Address of the proxy, as `host:port`.
+        addr: String,
+        /// Username and password for basic authentication, if required.
+        auth: Option<(String, String)>
+    },
+    /// A SOCKS5 proxy.
+    Socks5 {
+        /// Address of the proxy, as `host:port`.
+        addr: String,
+        /// Username and password, if required.
+        auth: Option<(String, String)>
+    }
+}
+
+/// TLS settings, used when the endpoint is a `wss://` URL.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// Extra root certificates to trust, in PEM format.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Don't trust the system's root certificates.
+    pub disable_built_in_roots: bool,
+    /// Accept invalid certificates. Only useful for testing!
+    pub accept_invalid_certs: bool,
+    /// Accept certificates that don't match the hostname. Only useful for testing!
+    pub accept_invalid_hostnames: bool
+}
+
+/// Settings used when connecting to WhatsApp Web.
+///
+/// The defaults connect directly to the real WhatsApp Web server, so
+/// you'll only need to change these if you're doing something unusual
+/// (like going through a proxy, or testing against a fake server).
+///
+/// ```rust,ignore
+/// let config = ConnectionConfig::new()
+///     .proxy(Proxy::Socks5 { addr: "localhost:1080".into(), auth: None })
+///     .ping_interval(Duration::from_secs(20));
+/// let conn = WebConnection::connect_persistent_with(sess, config).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub(crate) endpoint: String,
+    pub(crate) origin: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) proxy: Option<Proxy>,
+    pub(crate) ping_interval: Duration,
+    pub(crate) pong_deadline: Duration,
+    pub(crate) max_missed_pongs: u32,
+    pub(crate) max_qr_refreshes: u32,
+    pub(crate) tls: TlsConfig,
+    pub(crate) session_store: Option<Arc<dyn SessionStore>>
+}
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: ENDPOINT_URL.into(),
+            origin: ORIGIN_URL.into(),
+            headers: vec![],
+            proxy: None,
+            ping_interval: Duration::from_secs(13),
+            pong_deadline: Duration::from_secs(3),
+            max_missed_pongs: 1,
+            max_qr_refreshes: 5,
+            tls: TlsConfig::default(),
+            session_store: None
+        }
+    }
+}
+impl ConnectionConfig {
+    /// Make a new configuration, with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the websocket URL to connect to (`ws://` or `wss://`).
+    pub fn endpoint<T: Into<String>>(mut self, url: T) -> Self {
+        self.endpoint = url.into();
+        self
+    }
+    /// Set the value of the `Origin` header.
+    pub fn origin<T: Into<String>>(mut self, origin: T) -> Self {
+        self.origin = origin.into();
+        self
+    }
+    /// Add an extra header to the websocket request.
+    pub fn header<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+    /// Connect via a proxy server.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+    /// Set how often we ping the server to check the connection's alive.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+    /// Set how long we'll wait for a response to a ping before counting
+    /// it as missed.
+    pub fn pong_deadline(mut self, deadline: Duration) -> Self {
+        self.pong_deadline = deadline;
+        self
+    }
+    /// Set how many pings in a row can go unanswered before the connection
+    /// fails with `WaError::Timeout`.
+    ///
+    /// The default is 1 (i.e. fail as soon as a ping goes unanswered);
+    /// consider raising it on high-latency links. Any message from the
+    /// server counts as an answer.
+    pub fn max_missed_pongs(mut self, max: u32) -> Self {
+        self.max_missed_pongs = max.max(1);
+        self
+    }
+    /// Set how many times the QR code can be refreshed (after the previous
+    /// one expires) before a new login fails with `WaError::PairingTimeout`.
+    ///
+    /// Each code lasts for however long the server says, which is usually
+    /// about 20 seconds. The default is 5.
+    pub fn max_qr_refreshes(mut self, max: u32) -> Self {
+        self.max_qr_refreshes = max;
+        self
+    }
+    /// Set the TLS settings.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+    /// Save the session to the given store whenever one is established.
+    ///
+    /// See `WebConnection::connect_from_store()` for the other half.
+    pub fn session_store<S: SessionStore + 'static>(mut self, store: S) -> Self {
+        self.session_store = Some(Arc::new(store));
+        self
+    }
+    pub(crate) fn build_request(&self) -> Result<http::Request<()>> {
+        let mut req = http::Request::builder()
+            .uri(&self.endpoint as &str)
+            .header("Origin", &self.origin as &str);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k as &str, v as &str);
+        }
+        req.body(())
+            .map_err(|e| WaError::InvalidConfig(e.to_string()))
+    }
+}
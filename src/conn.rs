@@ -4,21 +4,23 @@
 //! using `futures` and somewhat sane coding practices.
 
 use tokio_tungstenite as ws;
-use ws::MaybeTlsStream;
 use ws::tungstenite::Message;
-use tokio::net::TcpStream;
 use std::collections::HashMap;
 use json::JsonValue;
 use qrcode::QrCode;
 use uuid::Uuid;
 use std::collections::VecDeque;
 use core::task::{Context, Poll};
-use futures::{Sink, Future, FutureExt, Stream};
+use futures::{Sink, Future, Stream};
+use futures::channel::mpsc;
 use tokio::time::{Interval, Delay};
 use std::time::{Duration, Instant};
 use std::pin::Pin;
 
 use crate::req::WaRequest;
+use crate::config::ConnectionConfig;
+use crate::transport::{self, Transport};
+use crate::handle::{ConnectionHandle, HandleRequest, Responder};
 use crate::session::{SessionState, PersistentSession};
 use crate::websocket_protocol::{WebsocketMessage, WebsocketMessagePayload, WebsocketMessageMetric};
 use crate::json_protocol::{self, ServerMessage};
@@ -27,28 +29,34 @@ use crate::message::{MessageId, Peer};
 use crate::event::WaEvent;
 use crate::node_wire::Node;
 use crate::errors::*;
-use crate::{crypto, Jid};
+use crate::{crypto, Jid, ProfilePicture};
 
-/// WhatsApp Web WebSocket endpoint URL.
-const ENDPOINT_URL: &str = "wss://web.whatsapp.com/ws";
-/// WhatsApp Web WebSocker origin header value.
-const ORIGIN_URL: &str = "https://web.whatsapp.com";
+type WsClient = ws::WebSocketStream<Box<dyn Transport>>;
 
-type WsClient = ws::WebSocketStream<MaybeTlsStream<TcpStream>>;
+/// How long a QR code lasts for, if the server doesn't tell us.
+const DEFAULT_QR_TTL: Duration = Duration::from_secs(20);
 
 #[derive(Clone, Debug)]
 pub(crate) enum CallbackType {
     /// Handle a login response for a new login.
     LoginNew,
+    /// Handle a fresh ref for the QR code, during a new login.
+    Reref,
     /// Handle a login response for a persistent login.
     LoginPersistent,
     /// Check that the returned `status` response has
     /// code 200, and throw an error if not.
     CheckStatus,
+    /// Like `CheckStatus`, but for our answer to a server challenge.
+    ChallengeResponse,
     /// Handle a received message ack after sending a message.
     ProcessAck { mid: MessageId },
     /// Handle returned message history after a message history query.
-    MessagesBefore { uuid: Uuid },
+    MessageHistory { uuid: Uuid },
+    /// Handle the results of a message search.
+    SearchResults { uuid: Uuid },
+    /// Handle the response to a message info query.
+    MessageInfo { mid: MessageId },
     /// Handle a file upload response.
     FileUpload { uuid: Uuid },
     /// Handle a media conn response.
@@ -59,6 +67,18 @@ pub(crate) enum CallbackType {
     ProfileStatus { jid: Jid },
     /// Handle a group metadata response.
     GroupMetadata,
+    /// Handle the response to checking whether a number is on WhatsApp.
+    NumberExists { number: String },
+    /// Handle the response to a group command.
+    GroupAck { jid: Option<Jid> },
+    /// Handle a group invite code response.
+    InviteCode { jid: Jid },
+    /// Handle a response to inspecting an invite code.
+    InviteInfo { code: String },
+    /// Handle a response to joining a group by invite code.
+    InviteJoin { code: String },
+    /// Handle the response to setting or removing a profile picture.
+    SetPicture { jid: Jid, removed: bool },
     /// Don't do anything.
     Noop
 }
@@ -72,7 +92,13 @@ pub(crate) enum CallbackType {
 /// This establishes a persistent session (`SessionEstablished` event),
 /// which you can reuse for future connections via the
 /// `WebConnection::connect_persistent()` method to avoid scanning the
-/// code again.
+/// code again. (Alternatively, configure a `SessionStore` and use
+/// `WebConnection::connect_from_store()`, which takes care of that for you.)
+///
+/// The `_with` variants of these methods take a `ConnectionConfig`, which
+/// lets you use a proxy, change the endpoint, and so on. If you want
+/// complete control over the underlying connection, the `_over` variants
+/// run the websocket over any `Transport` you give them.
 ///
 /// ## Usage
 ///
@@ -89,6 +115,10 @@ pub(crate) enum CallbackType {
 /// Some requests you can make, like getting a profile picture, will result
 /// in a corresponding event being generated. Often, you'll want to generate
 /// a `Uuid` to tie the event to the request you made.
+///
+/// Alternatively, get a `ConnectionHandle` via the `handle()` method, and
+/// use that to make requests and `await` their results directly. (You still
+/// need to poll the connection for this to work, though!)
 pub struct WebConnection {
     inner: WsClient,
     session_state: SessionState,
@@ -97,20 +127,95 @@ pub struct WebConnection {
     epoch: u32,
     ping_timer: Interval,
     response_timer: Option<Delay>,
+    /// Fires when the current QR code expires.
+    qr_timer: Option<Delay>,
+    /// How many times we've refreshed the QR code.
+    qr_refreshes: u32,
+    /// When the outstanding ping (if any) was sent.
+    ping_sent: Option<Instant>,
+    /// How many pings in a row have gone unanswered.
+    missed_pongs: u32,
+    latency: Option<Duration>,
+    /// Whether we've sent a logout request, and are waiting to close.
+    logging_out: bool,
+    /// Whether the websocket has been closed after logging out.
+    closed: bool,
     ws_outbox: VecDeque<ws::tungstenite::Message>,
     outbox: VecDeque<WaEvent>,
-    user_jid: Option<Jid>
+    user_jid: Option<Jid>,
+    state: ConnectionState,
+    config: ConnectionConfig,
+    /// Requests made through a `ConnectionHandle` that are waiting on
+    /// responses, by the tag of the first message they sent.
+    awaiting: HashMap<String, Awaiting>,
+    /// Which of those requests each outstanding message tag belongs to.
+    responders: HashMap<String, String>,
+    handle_tx: mpsc::UnboundedSender<HandleRequest>,
+    handle_rx: mpsc::UnboundedReceiver<HandleRequest>
 }
 impl std::marker::Unpin for WebConnection {}
 
+/// A request made through a `ConnectionHandle`, waiting on the responses
+/// to the messages it sent.
+struct Awaiting {
+    resp: Responder,
+    /// How many responses are still to come.
+    remaining: usize,
+    /// Events generated by the responses so far.
+    events: Vec<WaEvent>
+}
+
+/// Where a `WebConnection` has got to.
+///
+/// The current state is available from `WebConnection::state()`, and
+/// changes are signalled with `WaEvent::StateChanged`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The websocket is up, and we're waiting for the server to respond
+    /// to our initial request.
+    Connecting,
+    /// A QR code has been generated (new logins only), and we're waiting
+    /// for it to be scanned.
+    AwaitingScan,
+    /// We're logging in with a persistent session.
+    LoggingIn,
+    /// The server has challenged us to prove we hold the session's keys,
+    /// and we're waiting for it to accept our answer.
+    ChallengeInProgress,
+    /// A session has been established, and the connection is ready to use.
+    Established,
+    /// We're logging out, and will close the connection shortly.
+    Closing,
+    /// The connection has closed (or failed).
+    Closed
+}
+
 impl Stream for WebConnection {
     type Item = WaResult<WaEvent>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WaResult<WaEvent>>> {
+        let ret = self.as_mut().poll_events(cx);
+        match ret {
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                self.state = ConnectionState::Closed;
+                self.fail_awaiting();
+            },
+            _ => {}
+        }
+        ret
+    }
+}
+
+impl WebConnection {
+    fn poll_events(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WaResult<WaEvent>>> {
+        if self.logging_out {
+            return self.poll_logout(cx);
+        }
         while let Poll::Ready(m) = Pin::new(&mut self.inner).poll_next(cx)? {
             match m {
                 Some(m) => {
                     self.response_timer = None;
+                    self.missed_pongs = 0;
                     self.on_message(m)?;
                 },
                 None => {
@@ -118,12 +223,24 @@ impl Stream for WebConnection {
                 }
             }
         }
+        while let Poll::Ready(Some(hr)) = Pin::new(&mut self.handle_rx).poll_next(cx) {
+            self.on_handle_request(hr);
+        }
         if let Poll::Ready(_) = Pin::new(&mut self.ping_timer).poll_tick(cx) {
             self.on_ping_timer();
         }
-        match self.response_timer.as_mut().map(|mut x| Pin::new(&mut x).poll(cx)) {
-            Some(Poll::Ready(_)) => Err(WaError::Timeout)?,
-            _ => {}
+        if self.ws_outbox.len() > 0 {
+            // Requests made through a `ConnectionHandle` don't go through
+            // the `Sink`, so nobody else is going to flush them.
+            if let Poll::Ready(Err(e)) = self.as_mut().poll_flush(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+        if let Some(Poll::Ready(_)) = self.response_timer.as_mut().map(|mut x| Pin::new(&mut x).poll(cx)) {
+            self.on_response_timeout()?;
+        }
+        if let Some(Poll::Ready(_)) = self.qr_timer.as_mut().map(|mut x| Pin::new(&mut x).poll(cx)) {
+            self.on_qr_timer()?;
         }
         match self.outbox.pop_front() {
             Some(evt) => Poll::Ready(Some(Ok(evt))),
@@ -188,7 +305,8 @@ impl Sink<WaRequest> for WebConnection {
 
 impl WebConnection {
     // This `impl` block: connecting and instantiating
-    fn setup(sess: SessionState, ws: WsClient) -> Self {
+    fn setup(sess: SessionState, ws: WsClient, config: ConnectionConfig) -> Self {
+        let (handle_tx, handle_rx) = mpsc::unbounded();
         let mut ret = Self {
             inner: ws,
             session_state: sess,
@@ -197,64 +315,179 @@ impl WebConnection {
             epoch: 0,
             ws_outbox: VecDeque::new(),
             outbox: VecDeque::new(),
-            ping_timer: tokio::time::interval(Duration::new(13, 0)),
+            ping_timer: tokio::time::interval(config.ping_interval),
             response_timer: None,
-            user_jid: None
+            qr_timer: None,
+            qr_refreshes: 0,
+            ping_sent: None,
+            missed_pongs: 0,
+            latency: None,
+            logging_out: false,
+            closed: false,
+            user_jid: None,
+            state: ConnectionState::Connecting,
+            config,
+            awaiting: HashMap::new(),
+            responders: HashMap::new(),
+            handle_tx,
+            handle_rx
         };
         ret.on_connected();
         ret
     }
-    fn ws_connect(sess: SessionState) -> impl Future<Output=WaResult<Self>> {
-        let req = http::Request::builder()
-            .uri(ENDPOINT_URL)
-            .header("Origin", ORIGIN_URL)
-            .body(()).expect("invalid ENDPOINT_URL or ORIGIN_URL");
-
-        let fut = tokio_tungstenite::connect_async(req)
-            .map(|r| r
-                .map(|ws| WebConnection::setup(sess, ws.0))
-                .map_err(|e| WaError::from(e))
-            );
-        fut
+    async fn ws_connect(sess: SessionState, config: ConnectionConfig) -> WaResult<Self> {
+        let req = config.build_request()?;
+        let stream = transport::dial(&config, req.uri()).await?;
+        let stream = transport::wrap_tls(&config, req.uri(), stream).await?;
+        let (ws, _) = ws::client_async(req, stream).await?;
+        Ok(Self::setup(sess, ws, config))
+    }
+    async fn ws_connect_over(sess: SessionState, stream: Box<dyn Transport>, config: ConnectionConfig) -> WaResult<Self> {
+        let req = config.build_request()?;
+        let (ws, _) = ws::client_async(req, stream).await?;
+        Ok(Self::setup(sess, ws, config))
     }
     /// Connect to WhatsApp Web, starting a new session.
     pub fn connect_new() -> impl Future<Output=WaResult<Self>> {
-        Self::ws_connect(SessionState::pending_new())
+        Self::connect_new_with(ConnectionConfig::default())
     }
     /// Connect to WhatsApp Web, reusing an old persistent session.
     pub fn connect_persistent(sess: PersistentSession) -> impl Future<Output=WaResult<Self>> {
-        Self::ws_connect(SessionState::pending_persistent(sess))
+        Self::connect_persistent_with(sess, ConnectionConfig::default())
+    }
+    /// Like `connect_new()`, but using the given configuration.
+    pub fn connect_new_with(config: ConnectionConfig) -> impl Future<Output=WaResult<Self>> {
+        Self::ws_connect(SessionState::pending_new(), config)
+    }
+    /// Like `connect_persistent()`, but using the given configuration.
+    pub fn connect_persistent_with(sess: PersistentSession, config: ConnectionConfig) -> impl Future<Output=WaResult<Self>> {
+        Self::ws_connect(SessionState::pending_persistent(sess), config)
+    }
+    /// Connect using the session in the configured `SessionStore`, or start
+    /// a new session if there isn't one stored.
+    ///
+    /// Fails with `WaError::InvalidConfig` if no store is configured.
+    pub async fn connect_from_store(config: ConnectionConfig) -> WaResult<Self> {
+        let stored = match config.session_store {
+            Some(ref store) => store.load()?,
+            None => return Err(WaError::InvalidConfig("no session store configured".into()))
+        };
+        match stored {
+            Some(sess) => Self::connect_persistent_with(sess, config).await,
+            None => Self::connect_new_with(config).await
+        }
+    }
+    /// Start a new session, running the websocket over an already-open
+    /// `Transport` (instead of dialing the endpoint ourselves).
+    ///
+    /// The proxy and TLS settings in `config` are ignored, since you've
+    /// already dealt with that yourself.
+    pub fn connect_new_over<T: Transport>(transport: T, config: ConnectionConfig) -> impl Future<Output=WaResult<Self>> {
+        Self::ws_connect_over(SessionState::pending_new(), Box::new(transport), config)
+    }
+    /// Reuse an old persistent session, running the websocket over an
+    /// already-open `Transport`. See `connect_new_over()`.
+    pub fn connect_persistent_over<T: Transport>(sess: PersistentSession, transport: T, config: ConnectionConfig) -> impl Future<Output=WaResult<Self>> {
+        Self::ws_connect_over(SessionState::pending_persistent(sess), Box::new(transport), config)
+    }
+    /// Get the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+    /// Get the round-trip time of the most recently answered keepalive ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+    /// Get the configuration this connection was made with.
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+    /// Get a handle that can be used to make requests and await their results.
+    ///
+    /// Handles can be cloned, and used from other tasks; the requests will
+    /// be processed whenever this connection is next polled.
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle::new(self.handle_tx.clone())
+    }
+}
+impl WebConnection {
+    // This `impl` block: logging out
+    pub(crate) fn logout(&mut self) {
+        info!("Logging out");
+        self.ws_outbox.push_back(Message::Text(r#"goodbye,,["admin","Conn","disconnect"]"#.into()));
+        self.logging_out = true;
+        self.set_state(ConnectionState::Closing);
+    }
+    /// Send the logout request, then close the websocket.
+    ///
+    /// The server may well hang up on us as soon as it gets the request,
+    /// so errors here aren't worth reporting.
+    fn poll_logout(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WaResult<WaEvent>>> {
+        if !self.closed {
+            match self.as_mut().poll_flush(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => debug!("Error sending logout request: {}", e),
+                Poll::Ready(Ok(())) => {}
+            }
+            match Pin::new(&mut self.inner).poll_close(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => debug!("Error closing websocket after logout: {}", e),
+                Poll::Ready(Ok(())) => {}
+            }
+            self.closed = true;
+            self.set_state(ConnectionState::Closed);
+            self.ws_outbox.clear();
+            if let Some(store) = self.config.session_store.clone() {
+                if let Err(e) = store.delete() {
+                    error!("Failed to delete stored session after logout: {}", e);
+                }
+            }
+            self.outbox.push_back(WaEvent::LoggedOut);
+        }
+        Poll::Ready(self.outbox.pop_front().map(Ok))
     }
 }
 impl WebConnection {
     // This `impl` block: low-level protocol functions, like sending
     // and receiving different message types
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            debug!("Connection state: {:?} -> {:?}", self.state, state);
+            self.state = state;
+            self.outbox.push_back(WaEvent::StateChanged(state));
+        }
+    }
     fn alloc_message_tag(&mut self) -> String {
         let tag = self.tag_counter;
         self.tag_counter += 1;
         tag.to_string()
     }
-    fn send_ws_message(&mut self, msg: WebsocketMessage, ct: CallbackType) {
-        self.callbacks.insert(msg.tag.clone().into(), ct);
+    fn send_ws_message(&mut self, msg: WebsocketMessage, ct: CallbackType) -> String {
+        let tag: String = msg.tag.clone().into();
+        self.callbacks.insert(tag.clone(), ct);
         self.ws_outbox.push_back(msg.serialize());
+        tag
     }
     pub(crate) fn increment_epoch(&mut self) {
         self.epoch += 1;
     }
-    pub(crate) fn send_json_message(&mut self, message: JsonValue, ct: CallbackType) {
+    /// Emit an event straight away, rather than in response to the server.
+    pub(crate) fn push_event(&mut self, event: WaEvent) {
+        self.outbox.push_back(event);
+    }
+    pub(crate) fn send_json_message(&mut self, message: JsonValue, ct: CallbackType) -> String {
         let tag = self.alloc_message_tag();
         debug!("--> JSON (tag {}): {:?}", tag, message);
         self.send_ws_message(WebsocketMessage {
             tag: tag.into(),
             payload: WebsocketMessagePayload::Json(message)
-        }, ct);
+        }, ct)
     }
-    pub(crate) fn send_node_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, node: Node, ct: CallbackType) -> Result<()> {
+    pub(crate) fn send_node_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, node: Node, ct: CallbackType) -> Result<String> {
         debug!("--> node (tag {:?}): {:?}", tag, node);
-        self.send_binary_message(tag, metric, &node.serialize(), ct)?;
-        Ok(())
+        self.send_binary_message(tag, metric, &node.serialize(), ct)
     }
-    pub(crate) fn send_binary_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, message: &[u8], ct: CallbackType) -> Result<()> {
+    pub(crate) fn send_binary_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, message: &[u8], ct: CallbackType) -> Result<String> {
         let encrypted_message = if let SessionState::Established { ref persistent_session } = self.session_state {
             crypto::sign_and_encrypt_message(&persistent_session.enc, &persistent_session.mac, &message)
         } else {
@@ -263,35 +496,61 @@ impl WebConnection {
 
         let tag = tag.unwrap_or_else(|| self.alloc_message_tag());
         debug!("--> binary (tag {}): {:?}", tag, message);
-        self.send_ws_message(WebsocketMessage {
+        Ok(self.send_ws_message(WebsocketMessage {
             tag: tag.into(),
             payload: WebsocketMessagePayload::BinaryEphemeral(metric, &encrypted_message)
-        }, ct);
-        Ok(())
+        }, ct))
     }
-    pub(crate) fn send_set_app_event(&mut self, metric: WebsocketMessageMetric, evt: AppEvent) -> Result<()> {
+    pub(crate) fn send_set_app_event(&mut self, metric: WebsocketMessageMetric, evt: AppEvent) -> Result<String> {
         let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![evt]);
-        self.send_app_message(None, metric, msg, CallbackType::Noop)?;
-        Ok(())
+        self.send_app_message(None, metric, msg, CallbackType::Noop)
     }
-    pub(crate) fn send_app_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, ct: CallbackType) -> Result<()> {
-        self.epoch += 1;
-        let epoch = self.epoch;
-        self.send_node_message(tag, metric, app_message.serialize(epoch), ct)?;
-        Ok(())
+    pub(crate) fn send_star_messages(&mut self, messages: Vec<(Jid, MessageId, bool)>, starred: bool) -> Result<String> {
+        // Messages get grouped by chat, keeping the order they were given in.
+        let mut events: Vec<AppEvent> = vec![];
+        for (jid, id, from_me) in messages {
+            match events.iter_mut().find(|evt| match evt {
+                AppEvent::StarMessages { jid: j, .. } => j == &jid,
+                _ => false
+            }) {
+                Some(AppEvent::StarMessages { messages, .. }) => messages.push((id, from_me)),
+                _ => events.push(AppEvent::StarMessages { jid, starred, messages: vec![(id, from_me)] })
+            }
+        }
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), events);
+        self.send_app_message(None, WebsocketMessageMetric::Chat, msg, CallbackType::Noop)
+    }
+    pub(crate) fn send_app_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, ct: CallbackType) -> Result<String> {
+        let epoch = self.epoch + 1;
+        let node = app_message.serialize(epoch)?;
+        self.epoch = epoch;
+        self.send_node_message(tag, metric, node, ct)
     }
-    pub(crate) fn send_group_command(&mut self, command: GroupCommand, participants: Vec<Jid>) -> Result<()> {
+    pub(crate) fn send_group_command(&mut self, command: GroupCommand, participants: Vec<Jid>) -> Result<String> {
+        let inducer = self.user_jid.clone().ok_or(WaError::NoJidYet)?;
         let tag = self.alloc_message_tag();
+        let jid = command.jid().cloned();
 
-        let app_event = AppEvent::GroupCommand { inducer: self.user_jid.clone().unwrap(), participants, id: tag.clone(), command };
+        let app_event = AppEvent::GroupCommand { inducer, participants, id: tag.clone(), command };
 
         self.send_app_message(
             Some(tag),
             WebsocketMessageMetric::Group,
             AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![app_event]),
-            CallbackType::Noop
-        )?;
-        Ok(())
+            CallbackType::GroupAck { jid }
+        )
+    }
+    pub(crate) fn send_set_picture(&mut self, jid: Jid, picture: Option<ProfilePicture>) -> Result<String> {
+        let tag = self.alloc_message_tag();
+        let removed = picture.is_none();
+        let app_event = AppEvent::SetPicture { jid: jid.clone(), id: tag.clone(), picture };
+
+        self.send_app_message(
+            Some(tag),
+            WebsocketMessageMetric::Pic,
+            AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![app_event]),
+            CallbackType::SetPicture { jid, removed }
+        )
     }
     fn decrypt_binary_message(&mut self, encrypted_message: &[u8]) -> Result<Vec<u8>> {
         trace!("Decrypting binary message: {:?}", encrypted_message);
@@ -306,19 +565,29 @@ impl WebConnection {
     // This `impl` block: CallbackType impls
     fn ct_login_new(&mut self, p: JsonValue) -> Result<()> {
         let resp = json_protocol::parse_init_response(&p)?;
+        self.emit_scan_code(resp, json_protocol::parse_ref_ttl(&p))
+    }
+    fn ct_reref(&mut self, p: JsonValue) -> Result<()> {
+        let resp = json_protocol::parse_init_response(&p)?;
+        self.emit_scan_code(resp, json_protocol::parse_ref_ttl(&p))
+    }
+    fn emit_scan_code(&mut self, qr_ref: &str, ttl: Option<u64>) -> Result<()> {
         if let SessionState::PendingNew {
             ref public_key,
             ref client_id,
             ..
         } = self.session_state {
-            let qrc = QrCode::new(
-                format!("{},{},{}", resp, base64::encode(&public_key), base64::encode(&client_id))
-                )?;
-            self.outbox.push_back(WaEvent::ScanCode(qrc));
+            let pairing = format!("{},{},{}", qr_ref, base64::encode(&public_key), base64::encode(&client_id));
+            let code = QrCode::new(&pairing)?;
+            self.outbox.push_back(WaEvent::ScanCode { code, pairing });
+            self.set_state(ConnectionState::AwaitingScan);
         }
         else {
             return Err(WaError::InvalidSessionState);
         }
+        let ttl = ttl.map(Duration::from_millis).unwrap_or(DEFAULT_QR_TTL);
+        let deadline = tokio::time::Instant::from_std(Instant::now() + ttl);
+        self.qr_timer = Some(tokio::time::delay_until(deadline));
         Ok(())
     }
     fn ct_login_persistent(&mut self, p: JsonValue) -> Result<()> {
@@ -330,6 +599,7 @@ impl WebConnection {
                 &base64::encode(&persistent_session.client_id)
                 );
             self.send_json_message(login_command, CallbackType::CheckStatus);
+            self.set_state(ConnectionState::LoggingIn);
         }
         else {
             return Err(WaError::InvalidSessionState);
@@ -340,6 +610,13 @@ impl WebConnection {
         json_protocol::parse_response_status(&p)?;
         Ok(())
     }
+    fn ct_challenge_response(&mut self, p: JsonValue) -> Result<()> {
+        json_protocol::parse_response_status(&p)?;
+        if self.state == ConnectionState::ChallengeInProgress {
+            self.set_state(ConnectionState::LoggingIn);
+        }
+        Ok(())
+    }
     fn ct_process_ack(&mut self, p: JsonValue, mid: MessageId) -> Result<()> {
         use crate::message::{MessageAckLevel, MessageAckSide, MessageAck};
         use crate::json_protocol::LowLevelAck;
@@ -366,7 +643,7 @@ impl WebConnection {
         }
         Ok(())
     }
-    fn ct_messages_before(&mut self, uu: Uuid, n: Node) -> Result<()> {
+    fn ct_message_history(&mut self, uu: Uuid, n: Node) -> Result<()> {
         let resp = node_protocol::parse_message_response(n);
         self.outbox.push_back(WaEvent::MessageHistory {
             uuid: uu,
@@ -374,6 +651,16 @@ impl WebConnection {
         });
         Ok(())
     }
+    fn ct_search_results(&mut self, uuid: Uuid, n: Node) -> Result<()> {
+        let results = node_protocol::parse_search_response(n);
+        self.outbox.push_back(WaEvent::SearchResults { uuid, results });
+        Ok(())
+    }
+    fn ct_message_info(&mut self, mid: MessageId, n: Node) -> Result<()> {
+        let receipts = node_protocol::parse_message_info_response(n);
+        self.outbox.push_back(WaEvent::MessageInfo { mid, receipts });
+        Ok(())
+    }
     fn ct_file_upload(&mut self, p: JsonValue, uuid: Uuid) -> Result<()> {
         let resp = json_protocol::parse_file_upload_response(&p)?;
         self.outbox.push_back(WaEvent::FileUpload {
@@ -428,44 +715,158 @@ impl WebConnection {
         });
         Ok(())
     }
+    fn ct_number_exists(&mut self, j: JsonValue, number: String) -> Result<()> {
+        let jid = json_protocol::parse_exist_response(&j);
+        self.outbox.push_back(WaEvent::NumberChecked { number, jid });
+        Ok(())
+    }
+    fn ct_group_ack(&mut self, j: JsonValue, jid: Option<Jid>) -> Result<()> {
+        // Newly created groups only get a JID in the response.
+        let jid = jid.or_else(|| j["gid"].as_str().and_then(|gid| gid.parse().ok()));
+        match json_protocol::parse_response_status(&j) {
+            Err(WaError::StatusCode(status)) => {
+                self.outbox.push_back(WaEvent::GroupCommandFail { jid, status });
+            },
+            Err(e) => return Err(e),
+            Ok(()) => {
+                self.outbox.push_back(WaEvent::GroupAck { jid });
+            }
+        }
+        Ok(())
+    }
+    fn ct_set_picture(&mut self, j: JsonValue, jid: Jid, removed: bool) -> Result<()> {
+        // A reply without a status is malformed, rather than a success.
+        if j["status"].is_null() {
+            return Err(WaError::JsonFieldMissing("status"));
+        }
+        match json_protocol::parse_response_status(&j) {
+            Err(WaError::StatusCode(status)) => {
+                self.outbox.push_back(WaEvent::PictureChangeFail { jid, status });
+            },
+            Err(e) => return Err(e),
+            Ok(()) => {
+                self.outbox.push_back(WaEvent::PictureChange { jid, removed });
+            }
+        }
+        Ok(())
+    }
+    fn ct_invite_code(&mut self, j: JsonValue, jid: Jid) -> Result<()> {
+        let code = json_protocol::parse_invite_code_response(&j);
+        self.outbox.push_back(WaEvent::GroupInviteCode { jid, code });
+        Ok(())
+    }
+    fn ct_invite_info(&mut self, j: JsonValue, code: String) -> Result<()> {
+        let info = json_protocol::parse_invite_info_response(&j);
+        self.outbox.push_back(WaEvent::GroupInviteInfo { code, info });
+        Ok(())
+    }
+    fn ct_invite_join(&mut self, j: JsonValue, code: String) -> Result<()> {
+        let jid = json_protocol::parse_invite_join_response(&j);
+        self.outbox.push_back(WaEvent::GroupJoined { code, jid });
+        Ok(())
+    }
 }
 impl WebConnection {
     // This `impl` block: functions that get called to deal
     // with different messages coming down the wire
-    fn handle_callback_json(&mut self, j: JsonValue, c: CallbackType) -> Result<()> {
+    fn handle_callback_json(&mut self, j: JsonValue, c: CallbackType, awaiting: Option<String>) -> Result<()> {
         use self::CallbackType::*;
+        let start = self.outbox.len();
         let ret = match c.clone() {
             LoginNew => self.ct_login_new(j),
+            Reref => self.ct_reref(j),
             LoginPersistent => self.ct_login_persistent(j),
             CheckStatus => self.ct_check_status(j),
+            ChallengeResponse => self.ct_challenge_response(j),
             ProcessAck { mid }  => self.ct_process_ack(j, mid),
             FileUpload { uuid } => self.ct_file_upload(j, uuid),
             MediaConn { uuid } => self.ct_media_conn(j, uuid),
             ProfilePicture { jid } => self.ct_profile_picture(j, jid),
             ProfileStatus { jid } => self.ct_profile_status(j, jid),
             GroupMetadata => self.ct_group_metadata(j),
+            NumberExists { number } => self.ct_number_exists(j, number),
+            GroupAck { jid } => self.ct_group_ack(j, jid),
+            InviteCode { jid } => self.ct_invite_code(j, jid),
+            InviteInfo { code } => self.ct_invite_info(j, code),
+            InviteJoin { code } => self.ct_invite_join(j, code),
+            SetPicture { jid, removed } => self.ct_set_picture(j, jid, removed),
             Noop => Ok(()),
             x => Err(WaError::InvalidPayload(format!("{:?}", x), "json"))?
         };
+        if let Some(key) = awaiting {
+            self.respond(&key, start, ret);
+            return Ok(());
+        }
         if let Err(e) = ret {
             error!("Handler for {:?} failed: {}", c, e);
             Err(e)?
         }
         Ok(())
     }
-    fn handle_callback_node(&mut self, n: Node, c: CallbackType) -> Result<()> {
+    fn handle_callback_node(&mut self, n: Node, c: CallbackType, awaiting: Option<String>) -> Result<()> {
         use self::CallbackType::*;
+        let start = self.outbox.len();
         let ret: Result<()> = match c.clone() {
-            MessagesBefore { uuid } => self.ct_messages_before(uuid, n),
+            MessageHistory { uuid } => self.ct_message_history(uuid, n),
+            MessageInfo { mid } => self.ct_message_info(mid, n),
+            SearchResults { uuid } => self.ct_search_results(uuid, n),
             Noop => Ok(()),
             x => Err(WaError::InvalidPayload(format!("{:?}", x), "node"))?
         };
+        if let Some(key) = awaiting {
+            self.respond(&key, start, ret);
+            return Ok(());
+        }
         if let Err(e) = ret {
             error!("Handler for {:?} failed: {}", c, e);
             Err(e)?
         }
         Ok(())
     }
+    /// Hand the result of a callback (i.e. any events it generated from
+    /// `start` onwards) to whoever's awaiting it, instead of putting it in
+    /// the outbox.
+    ///
+    /// Errors are also sent to the awaiting future, instead of bringing
+    /// down the whole connection.
+    fn respond(&mut self, key: &str, start: usize, ret: Result<()>) {
+        let events = self.outbox.drain(start..).collect::<Vec<_>>();
+        // If an earlier response failed, the request has already been
+        // answered, so there's nothing left to do.
+        let mut awaiting = match self.awaiting.remove(key) {
+            Some(a) => a,
+            None => return
+        };
+        if let Err(e) = ret {
+            debug!("Handing callback error to awaiting handle: {}", e);
+            let _ = awaiting.resp.send(Err(e));
+            return;
+        }
+        awaiting.events.extend(events);
+        awaiting.remaining -= 1;
+        if awaiting.remaining == 0 {
+            let _ = awaiting.resp.send(Ok(awaiting.events));
+        }
+        else {
+            self.awaiting.insert(key.to_owned(), awaiting);
+        }
+    }
+    /// Fail every request still waiting on a response, once the
+    /// connection has gone away and none is coming.
+    fn fail_awaiting(&mut self) {
+        self.responders.clear();
+        self.callbacks.clear();
+        self.ws_outbox.clear();
+        for (_, awaiting) in self.awaiting.drain() {
+            let _ = awaiting.resp.send(Err(WaError::WebsocketDisconnected));
+        }
+        // Closing the channel makes any later requests through a handle
+        // fail straight away.
+        self.handle_rx.close();
+        while let Ok(hr) = self.handle_rx.try_recv() {
+            let _ = hr.resp.send(Err(WaError::WebsocketDisconnected));
+        }
+    }
     fn handle_connection_ack(&mut self, user_jid: Jid, client_token: &str, server_token: &str, secret: Option<&str>) -> Result<(PersistentSession, Jid)> {
         debug!("Handling connection ack");
         let (new_session_state, persistent_session, user_jid) = match self.session_state {
@@ -503,6 +904,15 @@ impl WebConnection {
         self.session_state = new_session_state;
         Ok((persistent_session, user_jid.unwrap()))
     }
+    fn save_session(&mut self, persistent: &PersistentSession) {
+        if let Some(ref store) = self.config.session_store {
+            debug!("Saving session to store");
+            if let Err(e) = store.save(persistent) {
+                error!("Failed to save session: {}", e);
+                self.outbox.push_back(WaEvent::SessionSaveFailed(e));
+            }
+        }
+    }
     fn handle_server_challenge(&mut self, challenge: &[u8]) -> Result<()> {
         trace!("Got server challenge: {:?}", challenge);
         debug!("Handling server challenge");
@@ -518,7 +928,8 @@ impl WebConnection {
             &base64::encode(&persist.client_id),
             signature.as_ref());
 
-        self.send_json_message(resp, CallbackType::CheckStatus);
+        self.send_json_message(resp, CallbackType::ChallengeResponse);
+        self.set_state(ConnectionState::ChallengeInProgress);
         Ok(())
     }
     fn generate_empty_ack(&mut self, mid: String) -> Result<()> {
@@ -546,6 +957,9 @@ impl WebConnection {
                 secret
             } => {
                 let (persistent, jid) = self.handle_connection_ack(user_jid, client_token, server_token, secret)?;
+                self.qr_timer = None;
+                self.save_session(&persistent);
+                self.set_state(ConnectionState::Established);
                 self.outbox.push_back(WaEvent::SessionEstablished { persistent, jid })
             },
             ChallengeRequest(challenge) => {
@@ -582,11 +996,67 @@ impl WebConnection {
         let init_command = json_protocol::build_init_request(base64::encode(&client_id).as_str());
         self.send_json_message(init_command, callback_type);
     }
+    fn on_handle_request(&mut self, hr: HandleRequest) {
+        let HandleRequest { req, resp } = hr;
+        let start = self.outbox.len();
+        let tags = match req.apply(Pin::new(&mut *self)) {
+            Ok(t) => t,
+            Err(e) => {
+                let _ = resp.send(Err(e));
+                return;
+            }
+        };
+        // Events emitted while sending the request belong to it, too.
+        let events = self.outbox.drain(start..).collect::<Vec<_>>();
+        let key = match tags.first() {
+            Some(k) => k.clone(),
+            None => {
+                // Nothing to wait for.
+                let _ = resp.send(Ok(events));
+                return;
+            }
+        };
+        for tag in tags.iter() {
+            self.responders.insert(tag.clone(), key.clone());
+        }
+        self.awaiting.insert(key, Awaiting { resp, remaining: tags.len(), events });
+    }
     fn on_ping_timer(&mut self) {
         self.ws_outbox.push_front(Message::Text("?,,".into()));
-        let deadline = tokio::time::Instant::from_std(Instant::now() + Duration::new(3, 0));
+        let now = Instant::now();
+        self.ping_sent = Some(now);
+        let deadline = tokio::time::Instant::from_std(now + self.config.pong_deadline);
         self.response_timer = Some(tokio::time::delay_until(deadline));
     }
+    fn on_qr_timer(&mut self) -> Result<()> {
+        self.qr_timer = None;
+        if self.qr_refreshes >= self.config.max_qr_refreshes {
+            error!("QR code not scanned after {} refreshes", self.qr_refreshes);
+            return Err(WaError::PairingTimeout);
+        }
+        self.qr_refreshes += 1;
+        debug!("QR code expired; requesting a new one ({} of {})", self.qr_refreshes, self.config.max_qr_refreshes);
+        self.send_json_message(json_protocol::build_reref_request(), CallbackType::Reref);
+        Ok(())
+    }
+    fn on_response_timeout(&mut self) -> Result<()> {
+        self.response_timer = None;
+        self.missed_pongs += 1;
+        if self.missed_pongs >= self.config.max_missed_pongs {
+            error!("Server failed to answer {} ping(s) in a row", self.missed_pongs);
+            return Err(WaError::Timeout);
+        }
+        warn!("Server didn't answer ping ({} of {} allowed missed)", self.missed_pongs, self.config.max_missed_pongs);
+        Ok(())
+    }
+    fn on_pong(&mut self) {
+        if let Some(sent) = self.ping_sent.take() {
+            let rtt = sent.elapsed();
+            debug!("Keepalive round-trip time: {:?}", rtt);
+            self.latency = Some(rtt);
+            self.outbox.push_back(WaEvent::Latency(rtt));
+        }
+    }
     fn on_message(&mut self, m: Message) -> Result<()> {
         trace!("<-- {:?}", m);
         let message = match WebsocketMessage::deserialize(&m) {
@@ -601,7 +1071,8 @@ impl WebConnection {
             WebsocketMessagePayload::Json(p) => {
                 if let Some(ct) = self.callbacks.remove(&message.tag as &str) {
                     debug!("<-- JSON (tag {} -> {:?}): {}", message.tag, ct, &p);
-                    self.handle_callback_json(p, ct)?;
+                    let responder = self.responders.remove(&message.tag as &str);
+                    self.handle_callback_json(p, ct, responder)?;
                 }
                 else {
                     debug!("<-- JSON (tag {}): {}", message.tag, &p);
@@ -634,7 +1105,8 @@ impl WebConnection {
                 };
                 if let Some(ct) = self.callbacks.remove(&message.tag as &str) {
                     debug!("<-- node (tag {} -> {:?}): {:?}", message.tag, ct, &payload);
-                    self.handle_callback_node(payload, ct)?;
+                    let responder = self.responders.remove(&message.tag as &str);
+                    self.handle_callback_node(payload, ct, responder)?;
                 }
                 else {
                     debug!("<-- node (tag {}): {:?}", message.tag, &payload);
@@ -658,6 +1130,7 @@ impl WebConnection {
             },
             WebsocketMessagePayload::Pong => {
                 debug!("<-- pong (tag {})", message.tag);
+                self.on_pong();
             },
             WebsocketMessagePayload::BinaryEphemeral(a, b) => {
                 // FIXME: I don't know what this is, but why are we ignoring it?
@@ -667,3 +1140,152 @@ impl WebConnection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::{SinkExt, StreamExt};
+    use futures::channel::oneshot;
+    use super::Message;
+    use crate::testing::*;
+    use crate::config::ConnectionConfig;
+    use crate::event::WaEvent;
+    use crate::errors::WaError;
+    use crate::req::WaRequest;
+    use crate::{WebConnection, ConnectionState};
+
+    #[tokio::test]
+    async fn test_keepalive_latency() {
+        let config = ConnectionConfig::new()
+            .ping_interval(Duration::from_millis(50));
+        let mut conn = connect_with(config, |mut client| async move {
+            // `recv()` answers pings for us.
+            while client.recv().await.is_ok() {}
+        }).await;
+        loop {
+            let evt = conn.next().await.expect("connection ended").expect("connection failed");
+            if let WaEvent::Latency(rtt) = evt {
+                assert_eq!(conn.latency(), Some(rtt));
+                break;
+            }
+        }
+    }
+    #[tokio::test]
+    async fn test_keepalive_missed_pongs() {
+        let config = ConnectionConfig::new()
+            .ping_interval(Duration::from_millis(50))
+            .pong_deadline(Duration::from_millis(20))
+            .max_missed_pongs(2);
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let mut conn = connect_with(config, |client| async move {
+            // Hold the connection open, but never answer pings.
+            let _ = done_rx.await;
+            drop(client);
+        }).await;
+        let start = Instant::now();
+        loop {
+            match conn.next().await {
+                Some(Ok(_)) => {},
+                Some(Err(WaError::Timeout)) => break,
+                Some(Err(e)) => panic!("unexpected error: {}", e),
+                None => panic!("connection ended")
+            }
+        }
+        // The first missed pong should have been tolerated.
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let _ = done_tx.send(());
+    }
+    #[tokio::test]
+    async fn test_logout() {
+        let (goodbye_tx, goodbye_rx) = oneshot::channel();
+        let mut conn = connect_with(ConnectionConfig::new(), |mut client| async move {
+            let _ = goodbye_tx.send(client.recv().await.unwrap());
+            while client.recv().await.is_ok() {}
+        }).await;
+        conn.send(WaRequest::Logout).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::LoggedOut => {},
+            _ => panic!("expected LoggedOut")
+        }
+        assert!(conn.next().await.is_none());
+        assert_eq!(goodbye_rx.await.unwrap(), Message::Text(r#"goodbye,,["admin","Conn","disconnect"]"#.into()));
+    }
+    #[tokio::test]
+    async fn test_qr_refresh_timeout() {
+        let mut server = MockServer::bind().await.unwrap();
+        let config = server.config()
+            .max_qr_refreshes(2);
+        tokio::spawn(async move {
+            let mut client = server.accept().await.unwrap();
+            client.set_ref_ttl(Duration::from_millis(20));
+            client.accept_init().await.unwrap();
+            client.accept_reref().await.unwrap();
+            client.accept_reref().await.unwrap();
+            client.hold_open().await
+        });
+        let mut conn = WebConnection::connect_new_with(config).await.unwrap();
+        let mut pairings = vec![];
+        loop {
+            match conn.next().await.expect("connection ended") {
+                Ok(WaEvent::ScanCode { pairing, .. }) => pairings.push(pairing),
+                Ok(_) => {},
+                Err(WaError::PairingTimeout) => break,
+                Err(e) => panic!("unexpected error: {}", e)
+            }
+        }
+        assert_eq!(pairings.len(), 3);
+        assert!(pairings[0] != pairings[1]);
+    }
+    #[tokio::test]
+    async fn test_state_changes() {
+        let sess = test_session();
+        let mut server = MockServer::bind().await.unwrap();
+        let config = server.config();
+        let server_sess = sess.clone();
+        tokio::spawn(async move {
+            let mut client = server.accept().await.unwrap();
+            client.login_persistent(&server_sess, &jid("447700900000@c.us"), true).await.unwrap();
+            client.hold_open().await
+        });
+        let mut conn = WebConnection::connect_persistent_with(sess, config).await.unwrap();
+        assert_eq!(conn.state(), ConnectionState::Connecting);
+        let mut states = vec![];
+        while states.last() != Some(&ConnectionState::Established) {
+            if let WaEvent::StateChanged(s) = conn.next().await.unwrap().unwrap() {
+                assert_eq!(conn.state(), s);
+                states.push(s);
+            }
+        }
+        assert_eq!(states, vec![
+            ConnectionState::LoggingIn,
+            ConnectionState::ChallengeInProgress,
+            ConnectionState::LoggingIn,
+            ConnectionState::Established
+        ]);
+    }
+    #[tokio::test]
+    async fn test_disconnect_fails_waiting_requests() {
+        let mut conn = connect_with(ConnectionConfig::new(), |mut client| async move {
+            // Take the request, then hang up without answering it.
+            client.recv_json().await.unwrap();
+        }).await;
+        let handle = conn.handle();
+        {
+            let request = handle.get_profile_status(jid("447700900001@c.us"));
+            let drive = async {
+                while let Some(Ok(_)) = conn.next().await {}
+            };
+            let (res, _) = tokio::time::timeout(Duration::from_secs(5), futures::future::join(request, drive)).await
+                .expect("request never failed");
+            match res {
+                Err(WaError::WebsocketDisconnected) => {},
+                _ => panic!("expected WebsocketDisconnected")
+            }
+        }
+        assert_eq!(conn.state(), ConnectionState::Closed);
+        match handle.get_profile_status(jid("447700900001@c.us")).await {
+            Err(WaError::WebsocketDisconnected) => {},
+            _ => panic!("expected WebsocketDisconnected")
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use ring::{agreement, rand, hkdf, hmac, digest, self};
+use std::num::NonZeroU32;
+use ring::{agreement, rand, hkdf, hmac, digest, pbkdf2, self};
 use ring::rand::{SystemRandom, SecureRandom};
 use crypto::{aes, blockmodes};
 use crypto::buffer::{RefWriteBuffer, RefReadBuffer, WriteBuffer};
@@ -51,6 +52,32 @@ pub(crate) fn calculate_secret_keys(secret: &[u8], private_key: agreement::Ephem
     Ok((enc, mac))
 }
 
+/// The phone's half of `calculate_secret_keys`: encrypt `enc` and `mac` for
+/// the client with the given public key, producing the `secret` sent in the
+/// `Conn` message.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn build_secret(client_public_key: &[u8], enc: &[u8], mac: &[u8]) -> Result<Vec<u8>> {
+    let (private_key, public_key) = generate_keypair();
+
+    let shared_key = agreement::agree_ephemeral(private_key, &agreement::X25519,
+                                                untrusted::Input::from(client_public_key), ring::error::Unspecified,
+                                                |key_material| {
+                                                    Ok(Vec::from(key_material))
+                                                })?;
+    let mut shared_key_expanded = [0u8; 80];
+
+    hkdf::extract_and_expand(&hmac::SigningKey::new(&digest::SHA256, &[0u8; 32]), &shared_key, &[], &mut shared_key_expanded);
+
+    let keys = [enc, mac].concat();
+    let mut keys_encrypted = [0u8; 80];
+    let size_with_padding = aes_encrypt(&shared_key_expanded[..32], &shared_key_expanded[64..], &keys, &mut keys_encrypted);
+
+    let signed = [&public_key[..], &keys_encrypted[..size_with_padding]].concat();
+    let signature = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, &shared_key_expanded[32..64]), &signed);
+
+    Ok([&public_key[..], signature.as_ref(), &keys_encrypted[..size_with_padding]].concat())
+}
+
 pub fn verify_and_decrypt_message(enc: &[u8], mac: &[u8], message_encrypted: &[u8]) -> Result<Vec<u8>> {
     hmac::verify(&hmac::VerificationKey::new(&digest::SHA256, &mac),
                  &message_encrypted[32..], &message_encrypted[..32]).map_err(|_| "Invalid mac")?;
@@ -85,6 +112,34 @@ pub(crate) fn sign_challenge(mac: &[u8], challenge: &[u8]) -> hmac::Signature {
     hmac::sign(&hmac::SigningKey::new(&digest::SHA256, &mac), &challenge)
 }
 
+const PASSPHRASE_ITERATIONS: u32 = 100_000;
+const PASSPHRASE_SALT_LEN: usize = 16;
+
+fn derive_passphrase_keys(passphrase: &[u8], salt: &[u8]) -> [u8; 64] {
+    let mut keys = [0u8; 64];
+    pbkdf2::derive(&digest::SHA256, NonZeroU32::new(PASSPHRASE_ITERATIONS).unwrap(), salt, passphrase, &mut keys);
+    keys
+}
+
+/// Encrypt some data with a passphrase, prepending the salt used.
+pub(crate) fn encrypt_with_passphrase(passphrase: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; PASSPHRASE_SALT_LEN];
+    SystemRandom::new().fill(&mut salt).unwrap();
+    let keys = derive_passphrase_keys(passphrase, &salt);
+    [&salt[..], &sign_and_encrypt_message(&keys[..32], &keys[32..], data)].concat()
+}
+
+/// Decrypt something encrypted with `encrypt_with_passphrase`.
+pub(crate) fn decrypt_with_passphrase(passphrase: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    // salt, HMAC, IV, and at least one block of ciphertext
+    if data.len() < PASSPHRASE_SALT_LEN + 32 + 16 + 16 {
+        bail_untyped!("encrypted data too short");
+    }
+    let (salt, data) = data.split_at(PASSPHRASE_SALT_LEN);
+    let keys = derive_passphrase_keys(passphrase, salt);
+    verify_and_decrypt_message(&keys[..32], &keys[32..], data)
+}
+
 fn derive_media_keys(key: &[u8], media_type: MediaType) -> [u8; 112] {
     let mut media_key_expanded = [0u8; 112];
     hkdf::extract_and_expand(&hmac::SigningKey::new(&digest::SHA256, &[0u8; 32]), key, match media_type {
@@ -92,6 +147,8 @@ fn derive_media_keys(key: &[u8], media_type: MediaType) -> [u8; 112] {
         MediaType::Video => b"WhatsApp Video Keys",
         MediaType::Audio => b"WhatsApp Audio Keys",
         MediaType::Document => b"WhatsApp Document Keys",
+        // Stickers are encrypted just like images.
+        MediaType::Sticker => b"WhatsApp Image Keys",
     }, &mut media_key_expanded);
     media_key_expanded
 }
@@ -223,6 +280,29 @@ mod tests {
         assert_eq!(msg, dec_msg);
     }
 
+    #[test]
+    fn test_secret_exchange() {
+        let mut enc = [0u8; 32];
+        SystemRandom::new().fill(&mut enc).unwrap();
+
+        let mut mac = [0u8; 32];
+        SystemRandom::new().fill(&mut mac).unwrap();
+
+        let (private_key, public_key) = generate_keypair();
+        let secret = build_secret(&public_key, &enc, &mac).unwrap();
+
+        assert_eq!(calculate_secret_keys(&secret, private_key).unwrap(), (enc, mac));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_passphrase() {
+        let data = b"{\"client_token\":\"hunter2\"}";
+        let enc = encrypt_with_passphrase(b"correct horse", data);
+
+        assert_eq!(decrypt_with_passphrase(b"correct horse", &enc).unwrap(), &data[..]);
+        assert!(decrypt_with_passphrase(b"battery staple", &enc).is_err());
+    }
+
     #[test]
     fn test_encrypt_decrypt_media() {
         let mut msg = vec![0u8; 300];
@@ -56,6 +56,12 @@ pub enum WaError {
         Io(io::Error),
         #[fail(display = "WebSocket error: {}", _0)]
         Websocket(tokio_tungstenite::tungstenite::Error),
+        #[fail(display = "TLS error: {}", _0)]
+        Tls(native_tls::Error),
+        #[fail(display = "proxy error: {}", _0)]
+        Proxy(String),
+        #[fail(display = "invalid connection config: {}", _0)]
+        InvalidConfig(String),
         #[fail(display = "Crypto error: {}", _0)]
         Crypto(ring::error::Unspecified),
         #[cfg(feature = "media")]
@@ -64,6 +70,12 @@ pub enum WaError {
         #[cfg(feature = "media")]
         #[fail(display = "http error code {}, message: {}", _0, _1)]
         HttpError(reqwest::StatusCode, String),
+        #[cfg(feature = "media")]
+        #[fail(display = "image error: {}", _0)]
+        Image(image::ImageError),
+        #[cfg(feature = "media")]
+        #[fail(display = "WebP error: {}", _0)]
+        WebP(image_webp::DecodingError),
         #[fail(display = "JSON error: {}", _0)]
         Json(json::Error),
         #[fail(display = "base64 decode error: {}", _0)]
@@ -84,14 +96,22 @@ pub enum WaError {
         InvalidTag(u8),
         #[fail(display = "invalid payload for {}: got {}", _0, _1)]
         InvalidPayload(String, &'static str),
+        #[fail(display = "timestamp out of range: {}", _0)]
+        InvalidTimestamp(i64),
         #[fail(display = "invalid session state for message")]
         InvalidSessionState,
         #[fail(display = "no jid yet to make an ack")]
         NoJidYet,
         #[fail(display = "invalid direction for outgoing message")]
         InvalidDirection,
+        #[fail(display = "can't serialize message content: {}", _0)]
+        UnsupportedContent(&'static str),
+        #[fail(display = "can't send action: {}", _0)]
+        UnsupportedAction(&'static str),
         #[fail(display = "connection timed out")]
         Timeout,
+        #[fail(display = "QR code wasn't scanned in time")]
+        PairingTimeout,
         #[fail(display = "websocket disconnected")]
         WebsocketDisconnected,
         #[fail(display = "timer failed")]
@@ -100,6 +120,14 @@ pub enum WaError {
         StatusCode(u16),
         #[fail(display = "disconnected from server")]
         Disconnected(DisconnectReason),
+        #[fail(display = "session serialization error: {}", _0)]
+        SessionSerialization(serde_json::Error),
+        #[fail(display = "failed to decrypt stored session (wrong passphrase?)")]
+        SessionDecrypt,
+        #[fail(display = "no response was generated for the request")]
+        NoResponse,
+        #[fail(display = "unexpected response to request")]
+        UnexpectedResponse,
         #[fail(display = "{}", _0)]
         UntypedOwned(String),
         #[fail(display = "{}", _0)]
@@ -113,13 +141,17 @@ pub(crate) type Result<T> = WaResult<T>;
 impl_from_for_error!(WaError,
                      Io => io::Error,
                      Websocket => tokio_tungstenite::tungstenite::Error,
+                     Tls => native_tls::Error,
                      Crypto => ring::error::Unspecified,
                      Json => json::Error,
                      Base64 => base64::DecodeError,
                      Protobuf => protobuf::ProtobufError,
                      Qr => qrcode::types::QrError,
+                     SessionSerialization => serde_json::Error,
                      UntypedOwned => String,
                      Untyped => &'static str);
 #[cfg(feature = "media")]
 impl_from_for_error!(WaError,
-                     Reqwest => reqwest::Error);
+                     Reqwest => reqwest::Error,
+                     Image => image::ImageError,
+                     WebP => image_webp::DecodingError);
@@ -3,13 +3,15 @@
 use qrcode::QrCode;
 use chrono::NaiveDateTime;
 use uuid::Uuid;
+use std::time::Duration;
 
 use crate::session::PersistentSession;
-use crate::message::{MessageId, ChatMessage};
-use crate::{Contact, Jid, Chat, ChatAction, GroupParticipantsChange, PresenceStatus, GroupMetadata};
+use crate::conn::ConnectionState;
+use crate::message::{MessageId, ChatMessage, MessageReceipt, SearchResults};
+use crate::{Contact, Jid, Chat, ChatAction, GroupParticipantsChange, GroupSetting, PresenceStatus, GroupMetadata, GroupInviteInfo};
 use crate::json_protocol::ServerMessage;
 use crate::node_protocol::AppMessage;
-use crate::errors::Result;
+use crate::errors::{Result, WaError};
 
 /// An event arising from a WhatsApp Web connection.
 pub enum WaEvent {
@@ -18,11 +20,23 @@ pub enum WaEvent {
     /// Note that this does not mean you can send messages yet;
     /// a session must still be negotiated!
     WebsocketConnected,
+    /// The state of the connection changed.
+    StateChanged(ConnectionState),
     /// A QR code is ready for the user to scan.
     ///
     /// This usually needs to be scanned within a few seconds of this
-    /// message being received in order to work.
-    ScanCode(QrCode),
+    /// message being received in order to work. When it expires, a fresh
+    /// one is requested automatically and another `ScanCode` is emitted
+    /// (see `ConnectionConfig::max_qr_refreshes()`); always display the
+    /// latest one.
+    ///
+    /// The `qr` module has functions to render the code in various formats.
+    ScanCode {
+        /// The QR code to display.
+        code: QrCode,
+        /// The raw string encoded in the QR code (`ref,pubkey,clientId`).
+        pairing: String
+    },
     /// A session has been successfully established, and the connection
     /// is now ready to use.
     ///
@@ -56,6 +70,15 @@ pub enum WaEvent {
         /// What happened.
         event: ChatAction
     },
+    /// Messages in a chat were starred or unstarred (probably on the phone).
+    StarChange {
+        /// The JID of the relevant chat.
+        jid: Jid,
+        /// Whether the messages were starred or unstarred.
+        starred: bool,
+        /// The IDs of the messages, and whether we sent each one.
+        messages: Vec<(MessageId, bool)>
+    },
     /// The presence of some known WhatsApp user changed.
     PresenceChange {
         /// The JID of the user.
@@ -122,6 +145,64 @@ pub enum WaEvent {
         /// The person who made the subject change.
         inducer: Jid
     },
+    /// The description of a group chat was set or cleared.
+    GroupDescriptionChange {
+        /// The JID of the group chat.
+        jid: Jid,
+        /// The new description (`None` if it was cleared).
+        description: Option<String>,
+        /// The ID of the new description, used when changing it again.
+        id: Option<String>,
+        /// The person who made the change.
+        inducer: Option<Jid>
+    },
+    /// One of a group chat's admin-only settings was turned on or off.
+    GroupSettingChange {
+        /// The JID of the group chat.
+        jid: Jid,
+        /// Which setting changed.
+        setting: GroupSetting,
+        /// Whether the setting is now on.
+        value: bool,
+        /// The person who made the change.
+        inducer: Option<Jid>
+    },
+    /// The server accepted a group request (creating a group, changing
+    /// its participants or settings, leaving it, ...).
+    ///
+    /// If the request fails, a `GroupCommandFail` event is emitted instead.
+    GroupAck {
+        /// The JID of the group chat, if known.
+        jid: Option<Jid>
+    },
+    /// The server rejected a group request.
+    GroupCommandFail {
+        /// The JID of the group chat, if known.
+        jid: Option<Jid>,
+        /// The returned status code from WhatsApp.
+        status: u16
+    },
+    /// A group chat's invite code was returned from a query or reset.
+    GroupInviteCode {
+        /// The JID of the group chat.
+        jid: Jid,
+        /// The invite code.
+        code: Result<String>
+    },
+    /// Information about the group chat an invite code is for.
+    GroupInviteInfo {
+        /// The invite code that was inspected.
+        code: String,
+        /// Information about the group.
+        info: Result<GroupInviteInfo>
+    },
+    /// We joined (or failed to join) a group chat using an invite code.
+    GroupJoined {
+        /// The invite code used.
+        code: String,
+        /// The JID of the group chat joined.
+        jid: Result<Jid>
+    },
     /// Someone changed or removed their profile picture.
     PictureChange {
         /// The JID of the relevant user.
@@ -129,6 +210,26 @@ pub enum WaEvent {
         /// Whether the picture was removed or not.
         removed: bool,
     },
+    /// Setting or removing a profile picture (with
+    /// `WaRequest::SetProfilePicture`) failed.
+    ///
+    /// If it succeeds, a `PictureChange` event is emitted instead.
+    PictureChangeFail {
+        /// The JID of the relevant user or group.
+        jid: Jid,
+        /// The returned status code from WhatsApp.
+        status: u16
+    },
+    /// The server told us whether a phone number is on WhatsApp, in
+    /// response to `WaRequest::CheckNumbers`.
+    NumberChecked {
+        /// The number, as it was given in the request.
+        number: String,
+        /// The canonical JID for the number, or `None` if it isn't
+        /// registered on WhatsApp. This is an error if the number was
+        /// malformed, or the server couldn't check it.
+        jid: Result<Option<Jid>>
+    },
     /// A profile picture was returned from a query.
     ProfilePicture {
         /// The JID of the relevant user.
@@ -153,6 +254,21 @@ pub enum WaEvent {
         /// The returned history messages.
         history: Result<Vec<ChatMessage>>
     },
+    /// Results of a message search were retrieved.
+    SearchResults {
+        /// The UUID associated with the search request.
+        uuid: Uuid,
+        /// The page of results.
+        results: Result<SearchResults>
+    },
+    /// Delivery and read receipts for a message we sent, from
+    /// `WaRequest::GetMessageInfo`.
+    MessageInfo {
+        /// The message ID.
+        mid: MessageId,
+        /// The furthest each recipient has got with the message.
+        receipts: Result<Vec<MessageReceipt>>
+    },
     /// A file upload URL was successfully retrieved.
     FileUpload {
         /// The UUID associated with the file upload request.
@@ -171,7 +287,38 @@ pub enum WaEvent {
         hosts: Vec<url::Host>
     },
     /// The phone's battery level changed to a number of percentage points.
-    BatteryLevel(u8)
+    BatteryLevel(u8),
+    /// The server answered a keepalive ping, taking this long to do so.
+    ///
+    /// The most recent value is also available from `WebConnection::latency()`.
+    Latency(Duration),
+    /// The session couldn't be saved to the configured `SessionStore`.
+    ///
+    /// The connection is still usable, but you'll probably want to save
+    /// the session from `SessionEstablished` some other way.
+    SessionSaveFailed(WaError),
+    /// We logged out (after a `WaRequest::Logout`), and the connection
+    /// has been closed.
+    ///
+    /// The session's credentials are no longer valid, so delete any copies
+    /// of the `PersistentSession` you've kept. This is always the last event.
+    LoggedOut,
+    /// The connection was lost, and a reconnection attempt is scheduled.
+    ///
+    /// Only emitted by `ReconnectingConnection`.
+    Reconnecting {
+        /// Which attempt this is (starting from 1).
+        attempt: u32,
+        /// How long we'll wait before trying.
+        delay: Duration
+    },
+    /// A lost connection was successfully reestablished.
+    ///
+    /// This is emitted after the `SessionEstablished` event for the new
+    /// connection, and any requests made while offline have now been sent.
+    ///
+    /// Only emitted by `ReconnectingConnection`.
+    Reconnected
 }
 impl WaEvent {
     pub(crate) fn from_app_message(a: AppMessage) -> Vec<Self> {
@@ -199,6 +346,9 @@ impl WaEvent {
                                 jid,
                                 event: action
                             }),
+                            AppEvent::StarMessages { jid, starred, messages } => Some(WaEvent::StarChange {
+                                jid, starred, messages
+                            }),
                             AppEvent::Battery(level) => Some(WaEvent::BatteryLevel(level)),
                             ae => {
                                 warn!("Received supposedly unreachable AppEvent: {:?}", ae);
@@ -221,11 +371,7 @@ impl WaEvent {
                 vec![WaEvent::PresenceChange {
                     jid,
                     presence: status,
-                    ts: time.and_then(|timestamp| if timestamp != 0 {
-                        Some(NaiveDateTime::from_timestamp(timestamp, 0))
-                    } else {
-                        None
-                    })
+                    ts: time
                 }]
             },
             MessageAck { message_id, level, sender, receiver, participant, time } => {
@@ -283,7 +429,6 @@ impl WaEvent {
                 vec![WaEvent::PictureChange { jid, removed }]
             }
             GroupSubjectChange { group, subject, subject_time, subject_owner } => {
-                let subject_time = NaiveDateTime::from_timestamp(subject_time, 0);
                 vec![WaEvent::GroupSubjectChange {
                     jid: group,
                     subject,
@@ -291,6 +436,12 @@ impl WaEvent {
                     inducer: subject_owner
                 }]
             },
+            GroupDescriptionChange { group, inducer, description, id } => {
+                vec![WaEvent::GroupDescriptionChange { jid: group, description, id, inducer }]
+            },
+            GroupSettingChange { group, inducer, setting, value } => {
+                vec![WaEvent::GroupSettingChange { jid: group, setting, value, inducer }]
+            },
             _ => panic!("WaEvent cannot convert from this ServerMessage")
         }
     }
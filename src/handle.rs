@@ -0,0 +1,349 @@
+//! Awaitable requests, as an alternative to correlating events by hand.
+//!
+//! Normally, the result of a request (like getting a profile picture) arrives
+//! as an event on the `WebConnection` stream, and you have to match it up with
+//! the request you made yourself. A `ConnectionHandle` does that for you:
+//! each method returns a future that resolves to the result of the request.
+
+use futures::channel::{mpsc, oneshot};
+use futures::Stream;
+use chrono::NaiveDateTime;
+
+use crate::event::WaEvent;
+use crate::req::{WaRequest, Uuid};
+use crate::message::{ChatMessage, MessageAck, MessageId, MessageReceipt, SearchResults};
+use crate::{Jid, GroupMetadata, MediaType};
+use crate::errors::*;
+
+/// Where the result of an awaited request gets sent: all of the events
+/// generated in response to it, in order.
+pub(crate) type Responder = oneshot::Sender<WaResult<Vec<WaEvent>>>;
+
+/// A request made through a `ConnectionHandle`.
+pub(crate) struct HandleRequest {
+    pub(crate) req: WaRequest,
+    pub(crate) resp: Responder
+}
+
+/// Details needed to upload media, as returned by `request_media_conn()`.
+#[derive(Debug, Clone)]
+pub struct MediaConn {
+    /// The auth string to be used on the media upload
+    pub auth: String,
+    /// The point in time when the auth stops being valid
+    pub ttl: NaiveDateTime,
+    /// List of hosts available for the upload
+    pub hosts: Vec<url::Host>
+}
+
+/// A handle to a `WebConnection`, used to make requests and await
+/// their results.
+///
+/// Obtain one of these with `WebConnection::handle()`. The connection
+/// must still be polled (as a `Stream`) for any of these futures to
+/// complete.
+///
+/// All of the futures returned fail with `WaError::WebsocketDisconnected`
+/// if the connection goes away before a response arrives, and with
+/// `WaError::StatusCode` if WhatsApp returns a non-200 status.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    tx: mpsc::UnboundedSender<HandleRequest>
+}
+impl ConnectionHandle {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<HandleRequest>) -> Self {
+        Self { tx }
+    }
+    /// Pass on a request made through another handle.
+    pub(crate) fn forward(&self, hr: HandleRequest) {
+        if let Err(e) = self.tx.unbounded_send(hr) {
+            let _ = e.into_inner().resp.send(Err(WaError::WebsocketDisconnected));
+        }
+    }
+    /// Make an arbitrary request, returning the event generated in
+    /// response to it (if any).
+    ///
+    /// For requests that generate more than one event, this returns the
+    /// first; use `request_all()` to get all of them.
+    pub async fn request(&self, req: WaRequest) -> WaResult<Option<WaEvent>> {
+        Ok(self.request_all(req).await?.into_iter().next())
+    }
+    /// Make an arbitrary request, returning all of the events generated
+    /// in response to it, in the order they happened.
+    pub async fn request_all(&self, req: WaRequest) -> WaResult<Vec<WaEvent>> {
+        let (resp, rx) = oneshot::channel();
+        self.tx.unbounded_send(HandleRequest { req, resp })
+            .map_err(|_| WaError::WebsocketDisconnected)?;
+        rx.await.map_err(|_| WaError::WebsocketDisconnected)?
+    }
+    /// Make a request that's expected to generate an event in response.
+    async fn request_event(&self, req: WaRequest) -> WaResult<WaEvent> {
+        self.request(req).await?.ok_or(WaError::NoResponse)
+    }
+    /// Send a message, resolving once the server has acknowledged it.
+    pub async fn send_message(&self, msg: ChatMessage) -> WaResult<MessageAck> {
+        match self.request_event(WaRequest::SendMessage(msg)).await? {
+            WaEvent::MessageAck(ack) => Ok(ack),
+            WaEvent::MessageSendFail { status, .. } => Err(WaError::StatusCode(status)),
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Get the URL of a user's profile picture, if they have one.
+    pub async fn get_profile_picture(&self, jid: Jid) -> WaResult<Option<String>> {
+        match self.request_event(WaRequest::GetProfilePicture(jid)).await? {
+            WaEvent::ProfilePicture { url, .. } => Ok(url),
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Get a user's status text.
+    pub async fn get_profile_status(&self, jid: Jid) -> WaResult<String> {
+        match self.request_event(WaRequest::GetProfileStatus(jid)).await? {
+            WaEvent::ProfileStatus { status, .. } => Ok(status),
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Get metadata about a group.
+    pub async fn get_group_metadata(&self, jid: Jid) -> WaResult<GroupMetadata> {
+        match self.request_event(WaRequest::GetGroupMetadata(jid)).await? {
+            WaEvent::GroupMetadata { meta } => meta,
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Check which of the given phone numbers are registered on WhatsApp,
+    /// returning the canonical JID for each number (or `None` if it isn't
+    /// registered), in the same order.
+    ///
+    /// A malformed number (or a failed check) only gives an error for
+    /// that number.
+    pub async fn check_numbers(&self, numbers: Vec<String>) -> WaResult<Vec<(String, WaResult<Option<Jid>>)>> {
+        let mut results = vec![];
+        for evt in self.request_all(WaRequest::CheckNumbers(numbers.clone())).await? {
+            match evt {
+                WaEvent::NumberChecked { number, jid } => results.push((number, jid)),
+                _ => return Err(WaError::UnexpectedResponse)
+            }
+        }
+        // The results come back in the order the server answered in.
+        numbers.into_iter()
+            .map(|number| {
+                let pos = results.iter().position(|(n, _)| n == &number).ok_or(WaError::NoResponse)?;
+                Ok(results.swap_remove(pos))
+            })
+            .collect()
+    }
+    /// Get up to `count` messages of history from before the given message.
+    ///
+    /// `from_me` says whether we sent that message.
+    pub async fn get_message_history_before(&self, jid: Jid, mid: MessageId, from_me: bool, count: u16) -> WaResult<Vec<ChatMessage>> {
+        let req = WaRequest::GetMessageHistoryBefore {
+            jid, mid, from_me, count,
+            uuid: Uuid::new_v4()
+        };
+        self.request_history(req).await
+    }
+    /// Get up to `count` messages of history from after the given message.
+    ///
+    /// `from_me` says whether we sent that message.
+    pub async fn get_message_history_after(&self, jid: Jid, mid: MessageId, from_me: bool, count: u16) -> WaResult<Vec<ChatMessage>> {
+        let req = WaRequest::GetMessageHistoryAfter {
+            jid, mid, from_me, count,
+            uuid: Uuid::new_v4()
+        };
+        self.request_history(req).await
+    }
+    /// Get up to `count` messages of history surrounding the given message.
+    ///
+    /// `from_me` says whether we sent that message.
+    pub async fn get_message_history_around(&self, jid: Jid, mid: MessageId, from_me: bool, count: u16) -> WaResult<Vec<ChatMessage>> {
+        let req = WaRequest::GetMessageHistoryAround {
+            jid, mid, from_me, count,
+            uuid: Uuid::new_v4()
+        };
+        self.request_history(req).await
+    }
+    /// Get the latest `count` messages in a chat.
+    pub async fn get_latest_messages(&self, jid: Jid, count: u16) -> WaResult<Vec<ChatMessage>> {
+        let req = WaRequest::GetLatestMessages {
+            jid, count,
+            uuid: Uuid::new_v4()
+        };
+        self.request_history(req).await
+    }
+    /// Walk backwards through a chat's whole history, `page_size` messages
+    /// at a time, starting with the latest messages.
+    ///
+    /// Each item is one page of history, in the order the server sent it.
+    /// Each page continues from the page's earliest message (by timestamp),
+    /// so the order doesn't matter. The stream ends when the server has no
+    /// older messages, or after an error.
+    pub fn history_stream(&self, jid: Jid, page_size: u16) -> impl Stream<Item = WaResult<Vec<ChatMessage>>> {
+        let handle = self.clone();
+        // The state is the oldest message seen so far (its ID, and whether
+        // we sent it), or `None` once the stream has finished.
+        futures::stream::unfold(Some(None), move |state: Option<Option<(MessageId, bool)>>| {
+            let handle = handle.clone();
+            let jid = jid.clone();
+            async move {
+                let page = match state? {
+                    None => handle.get_latest_messages(jid, page_size).await,
+                    Some((mid, from_me)) => handle.get_message_history_before(jid, mid, from_me, page_size).await
+                };
+                match page {
+                    Ok(ref msgs) if msgs.is_empty() => None,
+                    Ok(msgs) => {
+                        let oldest = msgs.iter().min_by_key(|m| m.time)
+                            .map(|m| (m.id.clone(), m.direction.is_sending()));
+                        Some((Ok(msgs), Some(oldest)))
+                    },
+                    Err(e) => Some((Err(e), None))
+                }
+            }
+        })
+    }
+    /// Get up to `count` starred messages, in one chat or (if `jid` is
+    /// `None`) across all chats.
+    ///
+    /// To get the next page, pass the ID of the last message returned, and
+    /// whether we sent it, as `before`.
+    pub async fn get_starred_messages(&self, jid: Option<Jid>, count: u16, before: Option<(MessageId, bool)>) -> WaResult<Vec<ChatMessage>> {
+        let req = WaRequest::GetStarredMessages {
+            jid, count, before,
+            uuid: Uuid::new_v4()
+        };
+        self.request_history(req).await
+    }
+    /// Search for messages containing `query`, in one chat or (if `jid`
+    /// is `None`) across all chats, returning the given page of results.
+    pub async fn search_messages(&self, query: String, jid: Option<Jid>, page: u32, count: u16) -> WaResult<SearchResults> {
+        let req = WaRequest::SearchMessages {
+            query, jid, page, count,
+            uuid: Uuid::new_v4()
+        };
+        match self.request_event(req).await? {
+            WaEvent::SearchResults { results, .. } => results,
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Find out who has received, read or played a message we sent.
+    pub async fn get_message_info(&self, jid: Jid, mid: MessageId) -> WaResult<Vec<MessageReceipt>> {
+        match self.request_event(WaRequest::GetMessageInfo { jid, mid }).await? {
+            WaEvent::MessageInfo { receipts, .. } => receipts,
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    async fn request_history(&self, req: WaRequest) -> WaResult<Vec<ChatMessage>> {
+        match self.request_event(req).await? {
+            WaEvent::MessageHistory { history, .. } => history,
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Get details needed to upload media.
+    pub async fn request_media_conn(&self) -> WaResult<MediaConn> {
+        let req = WaRequest::RequestMediaConn { uuid: Uuid::new_v4() };
+        match self.request_event(req).await? {
+            WaEvent::MediaConn { auth, ttl, hosts, .. } => Ok(MediaConn { auth, ttl, hosts }),
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+    /// Get a URL to upload a file with the given hash to.
+    pub async fn request_file_upload(&self, hash: Vec<u8>, media_type: MediaType) -> WaResult<String> {
+        let req = WaRequest::RequestFileUpload {
+            hash, media_type,
+            uuid: Uuid::new_v4()
+        };
+        match self.request_event(req).await? {
+            WaEvent::FileUpload { url, .. } => Ok(url),
+            _ => Err(WaError::UnexpectedResponse)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use crate::testing::*;
+    use crate::req::WaRequest;
+    use crate::event::WaEvent;
+    use crate::message::{ChatMessage, ChatMessageContent, Direction, Peer};
+
+    #[tokio::test]
+    async fn test_check_numbers() {
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let (tag1, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["query", "exist", "447700900001@c.us"]);
+            let (tag2, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["query", "exist", "447700900002@c.us"]);
+            // Answer out of order.
+            client.reply_status(&tag2, 404).await.unwrap();
+            client.send_json(&tag1, object!{ "status" => 200, "jid" => "447700900001@c.us" }).await.unwrap();
+            (client, ())
+        }).await;
+
+        conn.send(WaRequest::CheckNumbers(vec!["not a number".into()])).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::NumberChecked { number, jid: Err(_) } => assert_eq!(number, "not a number"),
+            _ => panic!("expected a failed NumberChecked")
+        }
+        let handle = spawn_handle(conn);
+        let numbers = vec!["+44 07700 900001".to_string(), "+44 0".to_string(), "0044 7700-900002".to_string()];
+        let results = handle.check_numbers(numbers.clone()).await.unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results.iter().map(|(n, _)| n).collect::<Vec<_>>(), numbers.iter().collect::<Vec<_>>());
+        assert_eq!(results[0].1.as_ref().unwrap(), &Some(jid("447700900001@c.us")));
+        assert!(results[1].1.is_err());
+        assert_eq!(results[2].1.as_ref().unwrap(), &None);
+    }
+    #[tokio::test]
+    async fn test_history_stream() {
+        history_stream_pages(false).await;
+        // The cursor comes from the earliest message, wherever it is.
+        history_stream_pages(true).await;
+    }
+    async fn history_stream_pages(newest_first: bool) {
+        let chat = jid("447700900001@c.us");
+        // The oldest message on the first page is one we sent, which the
+        // next query has to say.
+        let msgs = (0..3)
+            .map(|i| {
+                let mut msg = ChatMessage::new(chat.clone(), ChatMessageContent::Text(format!("message {}", i)));
+                msg.time = crate::parse_timestamp(1500000000 + i).unwrap();
+                if i != 1 {
+                    msg.direction = Direction::Receiving(Peer::Individual(chat.clone()));
+                }
+                msg
+            })
+            .collect::<Vec<_>>();
+        let order = move |mut page: Vec<ChatMessage>| {
+            if newest_first {
+                page.reverse();
+            }
+            page
+        };
+        let server_msgs = msgs.clone();
+        let (conn, _) = connect_scripted(move |mut client| async move {
+            let pages = vec![
+                ("last", None, order(server_msgs[1..].to_vec())),
+                ("before", Some((server_msgs[1].id.clone(), "true")), server_msgs[..1].to_vec()),
+                ("before", Some((server_msgs[0].id.clone(), "false")), vec![])
+            ];
+            for (kind, index, page) in pages {
+                let (tag, query) = client.recv_node().await.unwrap();
+                assert_eq!(query.desc, "query");
+                assert_eq!(query.attributes["kind"].as_str(), kind);
+                assert_eq!(query.attributes["count"].as_str(), "2");
+                let sent_index = query.attributes.get("index")
+                    .map(|i| (i.as_str().to_string(), query.attributes["owner"].as_str().to_string()));
+                assert_eq!(sent_index, index.map(|(id, owner)| (id.0, owner.to_string())));
+                client.send_node_tagged(&tag, history(page)).await.unwrap();
+            }
+            (client, ())
+        }).await;
+
+        let handle = spawn_handle(conn);
+        let pages = handle.history_stream(chat, 2)
+            .map(|page| page.unwrap().into_iter().map(|m| m.id).collect::<Vec<_>>())
+            .collect::<Vec<_>>().await;
+        let first_page = order(msgs[1..].to_vec()).into_iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(pages, vec![first_page, vec![msgs[0].id.clone()]]);
+    }
+}
@@ -4,7 +4,7 @@ use json::JsonValue;
 use chrono::NaiveDateTime;
 use base64;
 
-use super::{Jid, PresenceStatus, GroupMetadata, GroupParticipantsChange, MediaType};
+use super::{parse_timestamp, Jid, PresenceStatus, GroupMetadata, GroupParticipant, GroupRole, GroupInviteInfo, GroupParticipantsChange, GroupSetting, MediaType};
 use crate::message::MessageAckLevel;
 use crate::errors::*;
 
@@ -17,7 +17,7 @@ impl LowLevelAck {
     pub fn deserialize(json: &JsonValue) -> Result<Self> {
         let status_code = json["status"].as_u16().ok_or("status field wasn't an integer")?;
         let ts = json["t"].as_u64().ok_or("timestamp field wasn't a u64")?;
-        let ts = NaiveDateTime::from_timestamp(ts as i64, 0);
+        let ts = parse_timestamp(ts as i64)?;
         Ok(Self { status_code, timestamp: ts })
     }
 }
@@ -26,12 +26,14 @@ pub enum ServerMessage<'a> {
     ConnectionAck { user_jid: Jid, client_token: &'a str, server_token: &'a str, secret: Option<&'a str> },
     ChallengeRequest(Vec<u8>),
     Disconnect(Option<&'a str>),
-    PresenceChange { jid: Jid, status: PresenceStatus, time: Option<i64> },
-    MessageAck { message_id: &'a str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64 },
-    MessageAcks { message_ids: Vec<&'a str>, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64 },
+    PresenceChange { jid: Jid, status: PresenceStatus, time: Option<NaiveDateTime> },
+    MessageAck { message_id: &'a str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: NaiveDateTime },
+    MessageAcks { message_ids: Vec<&'a str>, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: NaiveDateTime },
     GroupIntroduce { newly_created: bool, inducer: Jid, meta: GroupMetadata },
     GroupParticipantsChange { group: Jid, change: GroupParticipantsChange, inducer: Option<Jid>, participants: Vec<Jid> },
-    GroupSubjectChange { group: Jid, subject: String, subject_time: i64, subject_owner: Jid },
+    GroupSubjectChange { group: Jid, subject: String, subject_time: NaiveDateTime, subject_owner: Jid },
+    GroupDescriptionChange { group: Jid, inducer: Option<Jid>, description: Option<String>, id: Option<String> },
+    GroupSettingChange { group: Jid, inducer: Option<Jid>, setting: GroupSetting, value: bool },
     PictureChange { jid: Jid, removed: bool },
     StatusChange(Jid, String),
 }
@@ -74,17 +76,15 @@ impl<'a> ServerMessage<'a> {
                 match cmd_type {
                     typ @ "introduce" | typ @ "create" => {
                         let group_metadata_json = &data[2];
-                        let admins_json = &group_metadata_json["admins"];
-                        let regulars_json = &group_metadata_json["regulars"];
-
-                        let mut participants = Vec::with_capacity(admins_json.len() + regulars_json.len());
-
-                        for participant in admins_json.members() {
-                            participants.push((Jid::from_str(participant.as_str().ok_or("not a string")?)?, true));
-                        }
-
-                        for participant in regulars_json.members() {
-                            participants.push((Jid::from_str(participant.as_str().ok_or("not a string")?)?, false));
+                        let mut participants = vec![];
+                        for &(key, role) in &[("superadmins", GroupRole::SuperAdmin), ("admins", GroupRole::Admin), ("regulars", GroupRole::Member)] {
+                            for participant in group_metadata_json[key].members() {
+                                let jid = Jid::from_str(participant.as_str().ok_or("not a string")?)?;
+                                // Superadmins might be listed as admins too.
+                                if !participants.iter().any(|p: &GroupParticipant| p.jid == jid) {
+                                    participants.push(GroupParticipant { jid, role });
+                                }
+                            }
                         }
 
                         ServerMessage::GroupIntroduce {
@@ -92,12 +92,18 @@ impl<'a> ServerMessage<'a> {
                             newly_created: typ == "create",
                             meta: GroupMetadata {
                                 id: chat,
-                                owner: None,
+                                owner: group_metadata_json["owner"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
                                 creation_time: group_metadata_json.get_i64("creation")?,
                                 subject: group_metadata_json.get_str("subject")?.to_string(),
                                 subject_owner: Jid::from_str(group_metadata_json.get_str("s_o")?)?,
                                 subject_time: group_metadata_json.get_i64("s_t")?,
-                                participants
+                                participants,
+                                description: group_metadata_json["desc"].as_str().map(|d| d.to_string()),
+                                description_id: group_metadata_json["descId"].as_str().map(|d| d.to_string()),
+                                description_owner: group_metadata_json["descOwner"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
+                                description_time: group_metadata_json["descTime"].as_i64(),
+                                announce: json_flag(&group_metadata_json["announce"]),
+                                restrict: json_flag(&group_metadata_json["restrict"])
                             }
                         }
                     }
@@ -120,7 +126,29 @@ impl<'a> ServerMessage<'a> {
                             subject_owner: inducer.ok_or("missing inducer")?,
                             group: chat,
                             subject: subject_json.get_str("subject")?.to_string(),
-                            subject_time: subject_json.get_i64("s_t")?
+                            subject_time: parse_timestamp(subject_json.get_i64("s_t")?)?
+                        }
+                    }
+                    "desc_add" | "desc_remove" => {
+                        let desc_json = &data[2];
+                        ServerMessage::GroupDescriptionChange {
+                            inducer,
+                            group: chat,
+                            description: if cmd_type == "desc_add" {
+                                Some(desc_json.get_str("desc")?.to_string())
+                            } else {
+                                None
+                            },
+                            id: desc_json["descId"].as_str().map(|id| id.to_string())
+                        }
+                    }
+                    "announce" | "restrict" => {
+                        let value = &data[2]["value"];
+                        ServerMessage::GroupSettingChange {
+                            inducer,
+                            group: chat,
+                            setting: if cmd_type == "announce" { GroupSetting::Announce } else { GroupSetting::Restrict },
+                            value: json_flag(value)
                         }
                     }
                     _ => bail_untyped! { "invalid or unsupported 'Chat' subcommand type {}", cmd_type}
@@ -134,7 +162,7 @@ impl<'a> ServerMessage<'a> {
                         sender: Jid::from_str(payload.get_str("from")?)?,
                         receiver: Jid::from_str(payload.get_str("to")?)?,
                         participant: payload["participant"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
-                        time: payload.get_i64("t")?,
+                        time: parse_timestamp(payload.get_i64("t")?)?,
                         level: MessageAckLevel::from_json(payload.get_u8("ack")?)?
                     },
                     "acks" => ServerMessage::MessageAcks {
@@ -142,7 +170,7 @@ impl<'a> ServerMessage<'a> {
                         sender: Jid::from_str(payload.get_str("from")?)?,
                         receiver: Jid::from_str(payload.get_str("to")?)?,
                         participant: payload["participant"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
-                        time: payload.get_i64("t")?,
+                        time: parse_timestamp(payload.get_i64("t")?)?,
                         level: MessageAckLevel::from_json(payload.get_u8("ack")?)?
                     },
                     _ => bail_untyped! { "invalid or unsupported 'Msg' or 'MsgInfo' subcommand type {}", cmd_type}
@@ -152,7 +180,8 @@ impl<'a> ServerMessage<'a> {
                 ServerMessage::PresenceChange {
                     jid: Jid::from_str(payload.get_str("id")?)?,
                     status: PresenceStatus::from_json(payload.get_str("type")?)?,
-                    time: payload["t"].as_i64()
+                    // A zero timestamp means there isn't one.
+                    time: payload["t"].as_i64().filter(|&t| t != 0).map(parse_timestamp).transpose()?
                 }
             }
             "Status" => {
@@ -193,7 +222,16 @@ impl GroupMetadata {
         let participants_json = &value["participants"];
         let mut participants = Vec::with_capacity(participants_json.len());
         for participant in participants_json.members() {
-            participants.push((Jid::from_str(participant.get_str("id")?)?, participant.get_bool("isAdmin")?));
+            let role = if json_flag(&participant["isSuperAdmin"]) {
+                GroupRole::SuperAdmin
+            }
+            else if participant.get_bool("isAdmin")? {
+                GroupRole::Admin
+            }
+            else {
+                GroupRole::Member
+            };
+            participants.push(GroupParticipant { jid: Jid::from_str(participant.get_str("id")?)?, role });
         }
 
         Ok(GroupMetadata {
@@ -203,11 +241,22 @@ impl GroupMetadata {
             participants,
             subject: value.get_str("subject")?.to_string(),
             subject_time: value.get_i64("subjectTime")?,
-            subject_owner: Jid::from_str(value.get_str("subjectOwner")?)?
+            subject_owner: Jid::from_str(value.get_str("subjectOwner")?)?,
+            description: value["desc"].as_str().map(|d| d.to_string()),
+            description_id: value["descId"].as_str().map(|d| d.to_string()),
+            description_owner: value["descOwner"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
+            description_time: value["descTime"].as_i64(),
+            announce: json_flag(&value["announce"]),
+            restrict: json_flag(&value["restrict"])
         })
     }
 }
 
+/// Read a flag that might be sent either as a boolean or as a string.
+fn json_flag(value: &JsonValue) -> bool {
+    value.as_bool().unwrap_or_else(|| value == "true" || value == "on")
+}
+
 impl GroupParticipantsChange {
     fn from_json(value: &str) -> Result<GroupParticipantsChange> {
         Ok(match value {
@@ -237,6 +286,15 @@ pub fn parse_init_response<'a>(response: &'a JsonValue) -> Result<&'a str> {
     response.get_str("ref")
 }
 
+pub fn build_reref_request() -> JsonValue {
+    array!["admin", "Conn", "reref"]
+}
+
+/// Get how long the ref in an init or reref response is valid for, in ms.
+pub fn parse_ref_ttl(response: &JsonValue) -> Option<u64> {
+    response["ttl"].as_u64()
+}
+
 pub fn build_takeover_request(client_token: &str, server_token: &str, client_id: &str) -> JsonValue {
     array!["admin", "login", client_token, server_token, client_id, "takeover"]
 }
@@ -255,6 +313,7 @@ pub fn build_file_upload_request(hash: &[u8], media_type: MediaType) -> JsonValu
         MediaType::Video => "video",
         MediaType::Audio => "audio",
         MediaType::Document => "document",
+        MediaType::Sticker => "image",
     }, base64::encode(hash)]
 }
 
@@ -309,6 +368,20 @@ pub fn parse_profile_status_response(response: &JsonValue) -> Option<&str> {
     response["status"].as_str()
 }
 
+pub fn build_exist_request(jid: &Jid) -> JsonValue {
+    array!["query", "exist", jid.to_string()]
+}
+
+/// Parse the response to an exist query, returning the canonical JID for
+/// the number, or `None` if it isn't on WhatsApp.
+pub fn parse_exist_response(response: &JsonValue) -> Result<Option<Jid>> {
+    match parse_response_status(response) {
+        Err(WaError::StatusCode(404)) => return Ok(None),
+        x => x?
+    }
+    Ok(Some(Jid::from_str(response.get_str("jid")?)?))
+}
+
 pub fn build_group_metadata_request(jid: &Jid) -> JsonValue {
     array!["query", "GroupMetadata", jid.to_string()]
 }
@@ -318,6 +391,45 @@ pub fn parse_group_metadata_response(response: &JsonValue) -> Result<GroupMetada
     GroupMetadata::from_json(response)
 }
 
+pub fn build_invite_code_request(jid: &Jid) -> JsonValue {
+    array!["query", "inviteCode", jid.to_string()]
+}
+
+pub fn build_invite_code_reset_request(jid: &Jid) -> JsonValue {
+    array!["action", "inviteReset", jid.to_string()]
+}
+
+/// Parse the response to an invite code query or reset.
+pub fn parse_invite_code_response(response: &JsonValue) -> Result<String> {
+    parse_response_status(response)?;
+    Ok(response.get_str("code")?.to_string())
+}
+
+pub fn build_invite_info_request(code: &str) -> JsonValue {
+    array!["query", "invite", code]
+}
+
+pub fn parse_invite_info_response(response: &JsonValue) -> Result<GroupInviteInfo> {
+    parse_response_status(response)?;
+    Ok(GroupInviteInfo {
+        id: Jid::from_str(response.get_str("id")?)?,
+        subject: response.get_str("subject")?.to_string(),
+        size: response["size"].as_u32(),
+        creation_time: response["creation"].as_i64(),
+        owner: response["owner"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
+        description: response["desc"].as_str().map(|d| d.to_string())
+    })
+}
+
+pub fn build_invite_join_request(code: &str) -> JsonValue {
+    array!["action", "invite", code]
+}
+
+pub fn parse_invite_join_response(response: &JsonValue) -> Result<Jid> {
+    parse_response_status(response)?;
+    Jid::from_str(response.get_str("gid")?)
+}
+
 pub trait JsonNonNull {
     fn get_str(&self, field: &'static str) -> Result<&str>;
     fn get_i64<'a>(&'a self, field: &'static str) -> Result<i64>;
@@ -342,3 +454,84 @@ impl JsonNonNull for JsonValue {
         self[field].as_bool().ok_or_else(|| WaError::JsonFieldMissing(field).into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use crate::testing::*;
+    use crate::event::WaEvent;
+    use crate::req::WaRequest;
+    use crate::GroupRole;
+
+    #[tokio::test]
+    async fn test_group_metadata() {
+        let group = jid("447700900000-1234567890@g.us");
+        let server_group = group.clone();
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let (tag, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["query", "GroupMetadata", server_group.to_string()]);
+            client.send_json(&tag, object!{
+                "status" => 200,
+                "id" => server_group.to_string(),
+                "owner" => "447700900000@c.us",
+                "subject" => "Book club",
+                "creation" => 1500000000,
+                "subjectTime" => 1500000000,
+                "subjectOwner" => "447700900000@c.us",
+                "desc" => "Reading things",
+                "descId" => "ABCDEF123456",
+                "descOwner" => "447700900001@c.us",
+                "descTime" => 1500000100,
+                "announce" => "true",
+                "participants" => array![
+                    object!{ "id" => "447700900000@c.us", "isAdmin" => true, "isSuperAdmin" => true },
+                    object!{ "id" => "447700900001@c.us", "isAdmin" => true, "isSuperAdmin" => false },
+                    object!{ "id" => "447700900002@c.us", "isAdmin" => false, "isSuperAdmin" => false }
+                ]
+            }).await.unwrap();
+            client.send_server_message(array!["Chat", object!{
+                "id" => server_group.to_string(),
+                "cmd" => "action",
+                "data" => array!["create", "447700900000@c.us", object!{
+                    "creation" => 1500000000,
+                    "subject" => "Book club",
+                    "s_o" => "447700900000@c.us",
+                    "s_t" => 1500000000,
+                    "superadmins" => array!["447700900000@c.us"],
+                    "admins" => array!["447700900000@c.us"],
+                    "regulars" => array!["447700900002@c.us"],
+                    "restrict" => true
+                }]
+            }]).await.unwrap();
+            (client, ())
+        }).await;
+
+        let roles = |meta: &crate::GroupMetadata| meta.participants.iter().map(|p| p.role).collect::<Vec<_>>();
+        conn.send(WaRequest::GetGroupMetadata(group.clone())).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupMetadata { meta } => {
+                let meta = meta.unwrap();
+                assert_eq!(roles(&meta), vec![GroupRole::SuperAdmin, GroupRole::Admin, GroupRole::Member]);
+                assert_eq!(meta.description.as_ref().map(|d| &d as &str), Some("Reading things"));
+                assert_eq!(meta.description_id.as_ref().map(|d| &d as &str), Some("ABCDEF123456"));
+                assert_eq!(meta.description_owner, Some(jid("447700900001@c.us")));
+                assert_eq!(meta.description_time, Some(1500000100));
+                assert!(meta.announce);
+                assert!(!meta.restrict);
+            },
+            _ => panic!("expected GroupMetadata")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::GroupIntroduce { newly_created, meta, .. } => {
+                assert!(newly_created);
+                assert_eq!(meta.id, group);
+                assert_eq!(roles(&meta), vec![GroupRole::SuperAdmin, GroupRole::Member]);
+                assert!(meta.participants[0].is_admin());
+                assert_eq!(meta.description, None);
+                assert!(!meta.announce);
+                assert!(meta.restrict);
+            },
+            _ => panic!("expected GroupIntroduce")
+        }
+    }
+}
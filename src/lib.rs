@@ -6,23 +6,33 @@
 #[macro_use] pub mod errors;
 pub mod event;
 pub mod conn;
+pub mod config;
+pub mod transport;
+pub mod reconnect;
+pub mod handle;
 pub mod req;
 pub mod message;
 #[cfg(feature = "media")]
 pub mod media;
 pub mod session;
+pub mod qr;
 mod message_wire;
 mod node_protocol;
 mod node_wire;
 mod json_protocol;
 mod websocket_protocol;
 pub mod crypto;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 use std::str::FromStr;
 use std::fmt;
 use crate::errors::*;
 
-pub use conn::WebConnection;
+pub use conn::{WebConnection, ConnectionState};
+pub use config::ConnectionConfig;
+pub use reconnect::ReconnectingConnection;
+pub use handle::ConnectionHandle;
 
 /// Jid used to identify either a group or an individual
 #[derive(Debug, Clone, PartialOrd, PartialEq, Ord, Eq, Hash)]
@@ -56,16 +66,69 @@ impl Jid {
         }
     }
 
-    pub fn from_phonenumber(mut phonenumber: String) -> Result<Jid> {
-        if phonenumber.starts_with('+') {
-            phonenumber.remove(0);
+    /// Make a Jid from an international phonenumber.
+    ///
+    /// Spaces, dashes, dots and brackets are ignored, and the number may
+    /// start with either `+` or `00`. A national trunk prefix written after
+    /// the country code is dropped, so "+44 (0)7700 900123" and
+    /// "+44 07700 900123" both become `447700900123`. This doesn't check
+    /// that the number is actually on WhatsApp; use
+    /// `WaRequest::CheckNumbers` for that.
+    pub fn from_phonenumber(phonenumber: String) -> Result<Jid> {
+        let mut number: String = phonenumber.chars()
+            .filter(|c| !c.is_whitespace() && !"-.()".contains(*c))
+            .collect();
+        if number.starts_with('+') {
+            number.remove(0);
+        }
+        else if number.starts_with("00") {
+            number.drain(..2);
+        }
+
+        if number.is_empty() || number.chars().any(|c| !c.is_digit(10)) {
+            return Err("not a valid phonenumber".into());
+        }
+        // No country code starts with 0, so this is a national number.
+        if number.starts_with('0') {
+            return Err("phonenumber has no country code".into());
         }
 
-        if phonenumber.chars().any(|c| !c.is_digit(10)) {
+        let cc = country_code_len(&number);
+        if number[cc..].starts_with('0') && !KEEPS_LEADING_ZERO.contains(&&number[..cc]) {
+            number.remove(cc);
+        }
+        if number.len() <= cc {
             return Err("not a valid phonenumber".into());
         }
 
-        Ok(Jid { id: phonenumber, is_group: false })
+        Ok(Jid { id: number, is_group: false })
+    }
+}
+
+/// Country codes where numbers really do start with 0, even when written
+/// internationally (e.g. Italian landlines), rather than it being a
+/// trunk prefix.
+const KEEPS_LEADING_ZERO: &[&str] = &["39", "225", "242", "378", "379"];
+
+/// How many digits of an international number are the country code.
+///
+/// Country codes are prefix-free: 1 and 7 are the only one-digit codes,
+/// the two-digit ones are listed here, and the rest have three digits.
+fn country_code_len(number: &str) -> usize {
+    const TWO_DIGITS: &[&str] = &[
+        "20", "27", "30", "31", "32", "33", "34", "36", "39", "40", "41", "43",
+        "44", "45", "46", "47", "48", "49", "51", "52", "53", "54", "55", "56",
+        "57", "58", "60", "61", "62", "63", "64", "65", "66", "81", "82", "84",
+        "86", "90", "91", "92", "93", "94", "95", "98"
+    ];
+    if number.starts_with('1') || number.starts_with('7') {
+        1
+    }
+    else if number.get(..2).map_or(false, |cc| TWO_DIGITS.contains(&cc)) {
+        2
+    }
+    else {
+        3.min(number.len())
     }
 }
 
@@ -118,15 +181,80 @@ pub enum PresenceStatus {
     Recording,
 }
 
+/// What a participant is allowed to do in a group chat.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupRole {
+    /// An ordinary member.
+    Member,
+    /// An admin.
+    Admin,
+    /// The group's creator, who is an admin that can't be demoted.
+    SuperAdmin,
+}
+
+/// A participant in a group chat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupParticipant {
+    pub jid: Jid,
+    pub role: GroupRole,
+}
+impl GroupParticipant {
+    /// Whether this participant is an admin (or the superadmin).
+    pub fn is_admin(&self) -> bool {
+        self.role != GroupRole::Member
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GroupMetadata {
     pub creation_time: i64,
     pub id: Jid,
     pub owner: Option<Jid>,
-    pub participants: Vec<(Jid, bool)>,
+    pub participants: Vec<GroupParticipant>,
     pub subject: String,
     pub subject_owner: Jid,
     pub subject_time: i64,
+    /// The group's description, if it has one.
+    pub description: Option<String>,
+    /// The ID of the description (needed to change it again).
+    pub description_id: Option<String>,
+    /// Who set the description.
+    pub description_owner: Option<Jid>,
+    /// When the description was set.
+    pub description_time: Option<i64>,
+    /// Whether only admins can send messages.
+    pub announce: bool,
+    /// Whether only admins can edit the group's info.
+    pub restrict: bool,
+}
+
+/// A profile picture, ready to be set with `WaRequest::SetProfilePicture`.
+///
+/// With the `media` feature, `media::generate_profile_picture()` makes
+/// one of these from any image.
+#[derive(Debug, Clone)]
+pub struct ProfilePicture {
+    /// The full-size picture: a 640x640 JPEG.
+    pub image: Vec<u8>,
+    /// A 96x96 JPEG preview of the picture.
+    pub preview: Vec<u8>,
+}
+
+/// Information about a group chat, from inspecting an invite code.
+#[derive(Debug, Clone)]
+pub struct GroupInviteInfo {
+    /// The JID of the group chat.
+    pub id: Jid,
+    /// The group's subject.
+    pub subject: String,
+    /// How many participants the group has.
+    pub size: Option<u32>,
+    /// When the group was created.
+    pub creation_time: Option<i64>,
+    /// The group's creator.
+    pub owner: Option<Jid>,
+    /// The group's description.
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -137,19 +265,58 @@ pub enum GroupParticipantsChange {
     Demote,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// A setting restricting what non-admins can do in a group chat.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupSetting {
+    /// Only admins can send messages (an 'announcement' group).
+    Announce,
+    /// Only admins can edit the group's subject, description and picture.
+    Restrict,
+}
+
+/// The last message in a chat, which some chat actions refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastMessage {
+    /// The ID of the message.
+    pub id: message::MessageId,
+    /// Whether we sent the message.
+    pub from_me: bool,
+    /// Who sent the message, if the chat is a group chat.
+    pub participant: Option<Jid>,
+}
+
+#[derive(Debug, Clone)]
 pub enum ChatAction {
+    /// Report the chat as spam.
     Add,
+    /// Delete the chat.
     Remove,
     Archive,
     Unarchive,
-    Clear,
+    /// Clear the chat's history, up to and including `last`.
+    Clear {
+        /// Whether starred messages get cleared too.
+        starred: bool,
+        last: Option<LastMessage>,
+    },
     Pin(i64),
-    Unpin,
+    /// Unpin the chat, given the time it was pinned at.
+    ///
+    /// Sending this requires the time to be present.
+    Unpin(Option<i64>),
     Mute(i64),
-    Unmute,
-    Read,
-    Unread,
+    /// Unmute the chat, given the time it was muted until.
+    ///
+    /// Sending this requires the time to be present.
+    Unmute(Option<i64>),
+    /// Mark the chat as read, up to and including the given message.
+    ///
+    /// Sending this requires the last message to be present.
+    Read(Option<LastMessage>),
+    /// Mark the chat as unread.
+    ///
+    /// Sending this requires the last message to be present.
+    Unread(Option<LastMessage>),
 }
 
 #[derive(Copy, Clone)]
@@ -158,4 +325,35 @@ pub enum MediaType {
     Video,
     Audio,
     Document,
+    Sticker,
+}
+
+/// Convert a UNIX timestamp (in seconds) from the server, failing with
+/// `WaError::InvalidTimestamp` if it's out of range.
+pub(crate) fn parse_timestamp(secs: i64) -> Result<chrono::NaiveDateTime> {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .map(|t| t.naive_utc())
+        .ok_or(WaError::InvalidTimestamp(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Jid;
+
+    #[test]
+    fn test_from_phonenumber() {
+        let id = |n: &str| Jid::from_phonenumber(n.to_string()).unwrap().id;
+        assert_eq!(id("+44 7700 900123"), "447700900123");
+        assert_eq!(id("+44 07700 900123"), "447700900123");
+        assert_eq!(id("0044 (0)7700-900123"), "447700900123");
+        assert_eq!(id("+1 (555) 010.0123"), "15550100123");
+        assert_eq!(id("+49 030 123456"), "4930123456");
+        assert_eq!(id("+353 087 1234567"), "353871234567");
+        // Italian landlines keep their 0.
+        assert_eq!(id("+39 06 1234 5678"), "390612345678");
+
+        for bad in &["", "+", "+44", "+44 0", "07700 900123", "+44 7700 9001x3", "not a number"] {
+            assert!(Jid::from_phonenumber(bad.to_string()).is_err(), "{:?} was accepted", bad);
+        }
+    }
 }
@@ -3,15 +3,18 @@ extern crate json;
 extern crate image;
 
 use std::io::Cursor;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 
-use image::{GenericImageView, RGB};
+use image::{DynamicImage, GenericImageView, FilterType, RGB};
 use image::jpeg::JPEGEncoder;
 use reqwest;
 use url::Host;
 
-use crate::MediaType;
+use crate::{MediaType, ProfilePicture};
 use crate::crypto;
-use crate::message::FileInfo;
+use crate::message::{ChatMessageContent, FileInfo, LinkPreview};
 use crate::errors::*;
 
 const USER_AGENT: &'static str = concat!("ww-rs-eta/", env!("CARGO_PKG_VERSION"));
@@ -20,13 +23,346 @@ pub fn generate_thumbnail_and_get_size(image: &[u8]) -> (Vec<u8>, (u32, u32)) {
     let image = image::load_from_memory(image).unwrap();
 
     let size = (image.height(), image.width());
-    let thumbnail = image.thumbnail(160, 160).to_rgb();
+    let thumbnail = encode_jpeg(&image.thumbnail(160, 160)).unwrap();
 
-    let mut thumbnail_writter = Cursor::new(Vec::new());
+    (thumbnail, size)
+}
+
+/// Crop an image to a square, and scale it to the sizes needed for a
+/// profile picture (for use with `WaRequest::SetProfilePicture`).
+pub fn generate_profile_picture(image: &[u8]) -> Result<ProfilePicture> {
+    let mut image = image::load_from_memory(image)?;
+
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    let square = image.crop((width - side) / 2, (height - side) / 2, side, side);
+
+    Ok(ProfilePicture {
+        image: encode_jpeg(&square.resize_exact(640, 640, FilterType::Lanczos3))?,
+        preview: encode_jpeg(&square.resize_exact(96, 96, FilterType::Lanczos3))?
+    })
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>> {
+    let image = image.to_rgb();
+    let mut writer = Cursor::new(Vec::new());
+    JPEGEncoder::new(&mut writer).encode(&image, image.width(), image.height(), RGB(8))?;
+    Ok(writer.into_inner())
+}
+
+/// Check that a sticker is a valid WebP image, and generate a PNG thumbnail
+/// for it.
+///
+/// Returns the thumbnail, the sticker's `(width, height)`, and whether
+/// it's animated (in which case the thumbnail shows the first frame).
+pub fn generate_sticker_thumbnail(webp: &[u8]) -> Result<(Vec<u8>, (u32, u32), bool)> {
+    let mut decoder = image_webp::WebPDecoder::new(Cursor::new(webp))?;
+    let (width, height) = decoder.dimensions();
+    let animated = decoder.is_animated();
+    let mut buf = vec![0; decoder.output_buffer_size().ok_or(WaError::Untyped("sticker too large"))?];
+    decoder.read_image(&mut buf)?;
+
+    let image = if decoder.has_alpha() {
+        image::RgbaImage::from_raw(width, height, buf).map(image::DynamicImage::ImageRgba8)
+    }
+    else {
+        image::RgbImage::from_raw(width, height, buf).map(image::DynamicImage::ImageRgb8)
+    };
+    let image = image.ok_or(WaError::Untyped("decoded sticker has the wrong size"))?;
 
-    JPEGEncoder::new(&mut thumbnail_writter).encode(&thumbnail, thumbnail.width(), thumbnail.height(), RGB(8)).unwrap();
+    let mut thumbnail = Vec::new();
+    image.thumbnail(100, 100).write_to(&mut thumbnail, image::ImageOutputFormat::PNG)?;
 
-    (thumbnail_writter.into_inner(), size)
+    Ok((thumbnail, (width, height), animated))
+}
+
+/// Fetch the page at `url`, and build a preview for it from its OpenGraph
+/// tags (falling back to `<title>` and `<meta name="description">`).
+///
+/// `url` should be the link exactly as it appears in the message text.
+/// The preview image is fetched too, if there is one; failing to get it
+/// isn't an error.
+///
+/// Since the link usually comes from someone else's message, only http(s)
+/// URLs on public addresses are fetched (for both the page and the image),
+/// the whole thing times out after `LINK_PREVIEW_TIMEOUT`, and responses
+/// larger than `MAX_PREVIEW_HTML` / `MAX_PREVIEW_IMAGE` are rejected.
+pub async fn fetch_link_preview(url: &str) -> Result<LinkPreview> {
+    let client = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(LINK_PREVIEW_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            // Redirect targets can't be resolved here, so this only catches
+            // literal addresses; `fetch_public()` checks where we actually
+            // ended up before reading anything.
+            if attempt.previous().len() >= 5 {
+                attempt.error("too many redirects")
+            }
+            else if !is_http(attempt.url()) || literal_host(attempt.url()).map_or(false, |ip| !is_public_address(ip)) {
+                attempt.error("redirected to a non-public URL")
+            }
+            else {
+                attempt.follow()
+            }
+        }))
+        .build()?;
+    let page_url = reqwest::Url::parse(url).map_err(|_| WaError::Untyped("invalid link preview URL"))?;
+    let response = fetch_public(&client, page_url).await?;
+
+    if response.status() != reqwest::StatusCode::from_u16(200).unwrap() {
+        return Err(WaError::HttpError(response.status(), response.text().await?));
+    }
+    let page_url = response.url().clone();
+    let html = read_capped(response, MAX_PREVIEW_HTML).await?;
+    let (mut preview, image) = parse_link_preview(url, &String::from_utf8_lossy(&html));
+
+    if let Some(image_url) = image.and_then(|i| page_url.join(&i).ok()) {
+        let thumbnail = async {
+            let response = fetch_public(&client, image_url).await?.error_for_status()?;
+            jpeg_thumbnail(&read_capped(response, MAX_PREVIEW_IMAGE).await?)
+        };
+        match thumbnail.await {
+            Ok(t) => preview.thumbnail = Some(t),
+            Err(e) => debug!("Couldn't get link preview image for {}: {}", url, e)
+        }
+    }
+    Ok(preview)
+}
+
+/// How long `fetch_link_preview()` waits for each request.
+pub const LINK_PREVIEW_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most HTML `fetch_link_preview()` will read.
+pub const MAX_PREVIEW_HTML: usize = 512 * 1024;
+/// The biggest preview image `fetch_link_preview()` will download.
+pub const MAX_PREVIEW_IMAGE: usize = 4 * 1024 * 1024;
+
+/// GET `url`, refusing anything that isn't http(s) or that resolves to
+/// a loopback, private or otherwise non-public address.
+async fn fetch_public(client: &reqwest::Client, url: reqwest::Url) -> Result<reqwest::Response> {
+    if !is_http(&url) {
+        bail_untyped!("only http(s) URLs can be previewed");
+    }
+    let host = url.host_str().ok_or(WaError::Untyped("URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs = tokio::net::lookup_host(format!("{}:{}", host, port));
+    let addrs = tokio::time::timeout(LINK_PREVIEW_TIMEOUT, addrs).await
+        .map_err(|_| WaError::Timeout)??
+        .collect::<Vec<_>>();
+    if addrs.is_empty() || addrs.iter().any(|a| !is_public_address(a.ip())) {
+        bail_untyped!("{} isn't a public address", host);
+    }
+
+    let response = client.get(url).send().await?;
+    // The name might resolve differently the second time round, or we
+    // might have been redirected somewhere else.
+    if response.remote_addr().map_or(false, |a| !is_public_address(a.ip())) {
+        bail_untyped!("{} isn't a public address", response.url());
+    }
+    Ok(response)
+}
+
+fn is_http(url: &reqwest::Url) -> bool {
+    url.scheme() == "http" || url.scheme() == "https"
+}
+
+/// The host of `url`, if it's written as an IP address.
+fn literal_host(url: &reqwest::Url) -> Option<IpAddr> {
+    url.host_str()?.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Whether `ip` is somewhere on the internet, rather than on this machine
+/// or the local network.
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local()
+              || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()
+              || ip.is_documentation()
+              || a == 0
+              // Carrier-grade NAT (100.64.0.0/10).
+              || (a == 100 && (b & 0xc0) == 64))
+        },
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ipv4_in_ipv6(&ip) {
+                return is_public_address(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+              // Unique local (fc00::/7) and link-local (fe80::/10).
+              || (first & 0xfe00) == 0xfc00
+              || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// The IPv4 address in an IPv4-mapped (`::ffff:a.b.c.d`) or
+/// IPv4-compatible (`::a.b.c.d`) IPv6 address.
+fn ipv4_in_ipv6(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] | [0, 0, 0, 0, 0, 0, _, _] if !ip.is_loopback() && !ip.is_unspecified() => {
+            let o = ip.octets();
+            Some(Ipv4Addr::new(o[12], o[13], o[14], o[15]))
+        },
+        _ => None
+    }
+}
+
+/// Read the body of `response`, failing if it's longer than `limit` bytes.
+async fn read_capped(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>> {
+    if response.content_length().map_or(false, |len| len > limit as u64) {
+        bail_untyped!("response from {} is too large", response.url());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            bail_untyped!("response from {} is too large", response.url());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Build a link preview from a page's HTML, also returning the URL
+/// of the preview image (if any).
+fn parse_link_preview(url: &str, html: &str) -> (LinkPreview, Option<String>) {
+    // Lowercasing ASCII doesn't move anything around, so offsets into
+    // `lower` work for `html` too.
+    let lower = html.to_ascii_lowercase();
+    let mut meta = HashMap::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<meta") {
+        let start = pos + start + "<meta".len();
+        let end = match tag_end(&lower[start..]) {
+            Some(end) => start + end,
+            None => break
+        };
+        let attrs = parse_attributes(&html[start..end]);
+        let key = attrs.get("property").or_else(|| attrs.get("name"));
+        if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+            meta.entry(key.to_ascii_lowercase()).or_insert_with(|| content.clone());
+        }
+        pos = end;
+    }
+    let title = lower.find("<title")
+        .and_then(|start| lower[start..].find('>').map(|end| start + end + 1))
+        .and_then(|start| lower[start..].find("</title").map(|end| &html[start..start + end]))
+        .map(|title| decode_entities(title.trim()))
+        .filter(|title| !title.is_empty());
+
+    let mut take = |key: &str| meta.remove(key).filter(|v: &String| !v.is_empty());
+    let preview = LinkPreview {
+        matched_text: url.to_owned(),
+        canonical_url: take("og:url"),
+        title: take("og:title").or(title),
+        description: take("og:description").or_else(|| take("description")),
+        thumbnail: None
+    };
+    (preview, take("og:image"))
+}
+
+/// Parse the attributes of an HTML tag (everything after the tag name).
+fn parse_attributes(s: &str) -> HashMap<String, String> {
+    let mut ret = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |&c| c.is_whitespace() || c == '/') {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '=' || c == '/' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            chars.next();
+        }
+        if name.is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            match chars.peek().cloned() {
+                Some(q) if q == '"' || q == '\'' => {
+                    chars.next();
+                    value.extend(chars.by_ref().take_while(|&c| c != q));
+                },
+                _ => {
+                    while let Some(&c) = chars.peek() {
+                        if c.is_whitespace() {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                }
+            }
+        }
+        ret.insert(name, decode_entities(&value));
+    }
+    ret
+}
+
+/// Find the `>` that ends a tag, skipping any inside quoted attribute values.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '>') => return Some(i),
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            _ => ()
+        }
+    }
+    None
+}
+
+/// Decode the HTML entities that turn up in titles and descriptions: the
+/// basic named ones and numeric character references. Anything else is
+/// left as it is.
+fn decode_entities(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        ret.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest[1..].find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| {
+                let name = &rest[1..end + 1];
+                let c = match name {
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    "amp" => Some('&'),
+                    "nbsp" => Some('\u{a0}'),
+                    _ if name.starts_with("#x") || name.starts_with("#X") => {
+                        u32::from_str_radix(&name[2..], 16).ok().and_then(std::char::from_u32)
+                    },
+                    _ if name.starts_with('#') => name[1..].parse().ok().and_then(std::char::from_u32),
+                    _ => None
+                };
+                c.map(|c| (c, end + 2))
+            });
+        match decoded {
+            Some((c, len)) => {
+                ret.push(c);
+                rest = &rest[len..];
+            },
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+fn jpeg_thumbnail(image: &[u8]) -> Result<Vec<u8>> {
+    encode_jpeg(&image::load_from_memory(image)?.thumbnail(160, 160))
 }
 
 /// Download file from servers and decrypt it
@@ -53,6 +389,7 @@ fn path_for(media_type: MediaType) -> [&'static str; 2] {
         MediaType::Video => ["mms", "video"],
         MediaType::Document => ["mms", "document"],
         MediaType::Audio => ["mms", "audio"],
+        MediaType::Sticker => ["mms", "image"],
     }
 }
 
@@ -101,3 +438,138 @@ pub async fn upload_file(file: &[u8], mime: String, media_type: MediaType, auth:
         key,
     })
 }
+
+/// Check and upload a WebP sticker, and build the message content to send it.
+///
+/// The sticker is validated (and its thumbnail generated) with
+/// `generate_sticker_thumbnail()` before anything is uploaded, so other
+/// image formats are rejected without touching the network.
+pub async fn upload_sticker(webp: &[u8], auth: &str, host: &Host) -> Result<ChatMessageContent> {
+    let (thumbnail, (width, height), animated) = generate_sticker_thumbnail(webp)?;
+    let info = upload_file(webp, "image/webp".into(), MediaType::Sticker, auth, host).await?;
+
+    Ok(ChatMessageContent::Sticker { info, width, height, animated, thumbnail })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sticker_thumbnail() {
+        let pixels = [255u8, 0, 0, 128].repeat(512 * 512);
+        let mut webp = Vec::new();
+        image_webp::WebPEncoder::new(&mut webp)
+            .encode(&pixels, 512, 512, image_webp::ColorType::Rgba8)
+            .unwrap();
+
+        let (thumbnail, size, animated) = generate_sticker_thumbnail(&webp).unwrap();
+        assert_eq!(size, (512, 512));
+        assert!(!animated);
+        let thumbnail = image::load_from_memory_with_format(&thumbnail, image::ImageFormat::PNG).unwrap();
+        assert_eq!(thumbnail.dimensions(), (100, 100));
+
+        assert!(generate_sticker_thumbnail(b"RIFF\0\0\0\0WEBPnope").is_err());
+    }
+
+    #[test]
+    fn test_upload_sticker_rejects_png() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(512, 512).write_to(&mut png, image::ImageOutputFormat::PNG).unwrap();
+
+        // Nothing listens on this host, so getting past validation would
+        // fail with a different error.
+        let host = Host::parse("127.0.0.1").unwrap();
+        let ret = futures::executor::block_on(upload_sticker(&png, "auth", &host));
+        match ret {
+            Err(WaError::WebP(_)) => (),
+            other => panic!("expected a WebP error, got {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn test_profile_picture() {
+        let image = DynamicImage::new_rgb8(800, 600);
+        let mut png = Vec::new();
+        image.write_to(&mut png, image::ImageOutputFormat::PNG).unwrap();
+
+        let pic = generate_profile_picture(&png).unwrap();
+        let full = image::load_from_memory_with_format(&pic.image, image::ImageFormat::JPEG).unwrap();
+        assert_eq!(full.dimensions(), (640, 640));
+        let preview = image::load_from_memory_with_format(&pic.preview, image::ImageFormat::JPEG).unwrap();
+        assert_eq!(preview.dimensions(), (96, 96));
+    }
+
+    #[test]
+    fn test_parse_link_preview() {
+        let html = r#"<html><head>
+            <TITLE>Fallback &amp; title</TITLE>
+            <meta charset="utf-8">
+            <meta property="og:title" content="Rust &quot;Programming&quot; Language" />
+            <meta name='description' content='A language empowering everyone'>
+            <META PROPERTY="og:image" CONTENT=/static/logo.png>
+            </head></html>"#;
+        let (preview, image) = parse_link_preview("rust-lang.org", html);
+        assert_eq!(preview.matched_text, "rust-lang.org");
+        assert_eq!(preview.canonical_url, None);
+        assert_eq!(preview.title.as_ref().unwrap(), "Rust \"Programming\" Language");
+        assert_eq!(preview.description.as_ref().unwrap(), "A language empowering everyone");
+        assert_eq!(image.as_ref().unwrap(), "/static/logo.png");
+
+        let (preview, image) = parse_link_preview("example.com", "<title>Fallback &amp; title</title>");
+        assert_eq!(preview.title.as_ref().unwrap(), "Fallback & title");
+        assert_eq!(image, None);
+    }
+
+    #[test]
+    fn test_parse_link_preview_quoting() {
+        let html = r#"<meta content="1 > 0, or 'so' they say" property="og:title">
+            <meta property='og:description' content='She said "hi" > bye'>
+            <meta property=og:url content=https://example.com/?a=1&amp;b=2>
+            <meta name="description" content="">
+            <meta property="og:image" content="">"#;
+        let (preview, image) = parse_link_preview("example.com", html);
+        assert_eq!(preview.title.as_ref().unwrap(), "1 > 0, or 'so' they say");
+        assert_eq!(preview.description.as_ref().unwrap(), "She said \"hi\" > bye");
+        assert_eq!(preview.canonical_url.as_ref().unwrap(), "https://example.com/?a=1&b=2");
+        assert_eq!(image, None);
+
+        // An unterminated tag is ignored rather than swallowing the rest.
+        let (preview, _) = parse_link_preview("example.com", r#"<title>t</title><meta property="og:title" content="x"#);
+        assert_eq!(preview.title.as_ref().unwrap(), "t");
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("&lt;b&gt; &quot;x&quot; &apos;y&#39;"), "<b> \"x\" 'y'");
+        // Only decoded once.
+        assert_eq!(decode_entities("&amp;lt; &amp;amp;"), "&lt; &amp;");
+        assert_eq!(decode_entities("it&#8217;s &#x1F980;&#X41;"), "it\u{2019}s \u{1F980}A");
+        // Things that aren't (known) entities are left alone.
+        assert_eq!(decode_entities("AT&T &bogus; &#xZZ; &#1114112; & &"), "AT&T &bogus; &#xZZ; &#1114112; & &");
+        assert_eq!(decode_entities("trailing &"), "trailing &");
+    }
+
+    #[test]
+    fn test_public_addresses() {
+        for ip in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+                    "0.0.0.0", "100.64.0.1", "255.255.255.255", "::1", "::", "fd00::1",
+                    "fe80::1", "::ffff:127.0.0.1", "::ffff:192.168.0.1"] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{} is public", ip);
+        }
+        for ip in &["1.1.1.1", "100.128.0.1", "2606:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_public_address(ip.parse().unwrap()), "{} isn't public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_preview_rejects_non_public_urls() {
+        for url in &["file:///etc/passwd", "ftp://example.com/", "http://127.0.0.1:1/",
+                     "http://[::1]:1/", "http://localhost:1/", "http://169.254.169.254/latest/meta-data/"] {
+            match fetch_link_preview(url).await {
+                Err(WaError::Untyped(_)) | Err(WaError::UntypedOwned(_)) => (),
+                other => panic!("{} wasn't rejected: {:?}", url, other.map(|_| ()))
+            }
+        }
+    }
+}
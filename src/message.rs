@@ -7,7 +7,7 @@ use ring::rand::{SystemRandom, SecureRandom};
 use chrono::NaiveDateTime;
 
 use super::message_wire;
-use super::Jid;
+use super::{Jid, parse_timestamp};
 use crate::errors::*;
 
 macro_rules! get_fileinfo {
@@ -22,6 +22,16 @@ macro_rules! get_fileinfo {
         }
     }
 }
+macro_rules! set_fileinfo {
+    ($msg:ident, $info:ident) => {
+        $msg.set_url($info.url);
+        $msg.set_mimetype($info.mime);
+        $msg.set_fileSha256($info.sha256);
+        $msg.set_fileEncSha256($info.enc_sha256);
+        $msg.set_fileLength($info.size as u64);
+        $msg.set_mediaKey($info.key);
+    }
+}
 macro_rules! get_caption {
     ($msg:ident) => {
         if $msg.has_caption() {
@@ -35,7 +45,7 @@ macro_rules! get_caption {
 macro_rules! get_context_info {
     ($msg:expr) => {
         if $msg.has_contextInfo() {
-            QuotedChatMessage::from_context_info($msg.take_contextInfo())?
+            Some($msg.take_contextInfo())
         }
         else {
             None
@@ -127,10 +137,13 @@ pub struct MessageAck {
 }
 
 impl MessageAck {
-    pub fn from_server_message(message_id: &str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64, own_jid: &Jid) -> MessageAck {
+    /// Build an ack from the fields of a server `Msg`/`MsgInfo` message.
+    ///
+    /// `time` is the already-parsed timestamp; see `parse_timestamp()`.
+    pub fn from_server_message(message_id: &str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: NaiveDateTime, own_jid: &Jid) -> MessageAck {
         MessageAck {
             level,
-            time: Some(NaiveDateTime::from_timestamp(time, 0)),
+            time: Some(time),
             id: MessageId(message_id.to_string()),
             side: if own_jid == &sender {
                 MessageAckSide::There(if let Some(participant) = participant {
@@ -170,8 +183,29 @@ impl MessageAck {
     }
 }
 
-/// Information about a file.
+/// How far one recipient of a message we sent has got with it, as returned
+/// by `WaRequest::GetMessageInfo`.
+#[derive(Debug, Clone)]
+pub struct MessageReceipt {
+    /// The recipient.
+    pub jid: Jid,
+    /// The furthest the recipient has got (`Received`, `Read` or `Played`).
+    pub level: MessageAckLevel,
+    /// When they got there.
+    pub time: Option<NaiveDateTime>,
+}
+
+/// A page of messages found by `WaRequest::SearchMessages`.
 #[derive(Debug, Clone)]
+pub struct SearchResults {
+    /// The messages found.
+    pub messages: Vec<ChatMessage>,
+    /// Whether this is the last page of results.
+    pub last_page: bool,
+}
+
+/// Information about a file.
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileInfo {
     /// The URL where this file is hosted.
     pub url: String,
@@ -187,11 +221,37 @@ pub struct FileInfo {
     pub key: Vec<u8>,
 }
 
+/// Preview information for a link in a text message, shown as a card
+/// underneath the text.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkPreview {
+    /// The link, exactly as it appears in the message text.
+    pub matched_text: String,
+    /// The canonical URL of the linked page, if different.
+    pub canonical_url: Option<String>,
+    /// The page title.
+    pub title: Option<String>,
+    /// A short description of the page.
+    pub description: Option<String>,
+    /// JPEG thumbnail of the page's preview image.
+    pub thumbnail: Option<Vec<u8>>
+}
+
 /// The content of a WhatsApp message.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ChatMessageContent {
     /// A simple, plain text message.
     Text(String),
+    /// A text message with a preview of a link in it.
+    ///
+    /// With the `media` feature, `media::fetch_link_preview()` can fill in
+    /// the preview for you.
+    TextWithPreview {
+        /// The message text, which should contain the link.
+        text: String,
+        /// Preview information for the link.
+        preview: LinkPreview
+    },
     /// An image.
     Image {
         /// Information about the image file itself.
@@ -230,6 +290,21 @@ pub enum ChatMessageContent {
         /// The supplied filename.
         filename: String
     },
+    /// A sticker (a WebP image, which might be animated).
+    ///
+    /// Download it with `media::download_file()` and `MediaType::Sticker`.
+    Sticker {
+        /// Information about the sticker file itself.
+        info: FileInfo,
+        /// Width in pixels.
+        width: u32,
+        /// Height in pixels.
+        height: u32,
+        /// Whether the sticker is animated.
+        animated: bool,
+        /// PNG thumbnail of the sticker (may be empty).
+        thumbnail: Vec<u8>
+    },
     /// An uploaded contact card (i.e. vCard).
     Contact {
         /// The name of the contact.
@@ -237,6 +312,21 @@ pub enum ChatMessageContent {
         /// The contact card, in vCard format.
         vcard: String
     },
+    /// An invitation to join a group chat.
+    GroupInvite {
+        /// The JID of the group chat.
+        jid: Jid,
+        /// The invite code (which can be used with `WaRequest::JoinGroup`).
+        code: String,
+        /// When the invite expires.
+        expiration: Option<NaiveDateTime>,
+        /// The group's name.
+        group_name: String,
+        /// JPEG thumbnail of the group's picture.
+        thumbnail: Vec<u8>,
+        /// Invitation caption, if there is one.
+        caption: Option<String>
+    },
     /// A location somewhere on earth.
     Location {
         /// Degrees latitude.
@@ -264,6 +354,8 @@ pub enum ChatMessageContent {
         seq: Option<i64>
     },
     /// A redaction (i.e. someone 'deleting' a message they've sent).
+    ///
+    /// To revoke one of your own messages, see `ChatMessage::revoke()`.
     Redaction {
         /// The message ID being deleted.
         mid: MessageId
@@ -282,6 +374,7 @@ impl ChatMessageContent {
             Text(ref st) => {
                 st.to_owned()
             },
+            TextWithPreview { ref text, .. } => text.to_owned(),
             Image { ref caption, .. } => {
                 if let Some(c) = caption {
                     format!("Image: {}", c)
@@ -300,7 +393,9 @@ impl ChatMessageContent {
             },
             Audio { .. } => "Audio".into(),
             Document { ref filename, .. } => format!("Document: {}", filename),
+            Sticker { .. } => "Sticker".into(),
             Contact { ref display_name, .. } => format!("Contact: {}", display_name),
+            GroupInvite { ref group_name, .. } => format!("Group invite: {}", group_name),
             Location { lat, long, .. } => format!("Location: ({}, {})", lat, long),
             LiveLocation { lat, long, .. } => format!("Live location: ({}, {})", lat, long),
             Redaction { ref mid } => format!("Redaction of {}", mid.0),
@@ -324,7 +419,17 @@ impl ChatMessageContent {
         }
         if message.has_extendedTextMessage() {
             let mut etm = message.take_extendedTextMessage();
-            return Ok(Text(etm.take_text()));
+            if !etm.has_matchedText() {
+                return Ok(Text(etm.take_text()));
+            }
+            let preview = LinkPreview {
+                matched_text: etm.take_matchedText(),
+                canonical_url: if etm.has_canonicalUrl() { Some(etm.take_canonicalUrl()) } else { None },
+                title: if etm.has_title() { Some(etm.take_title()) } else { None },
+                description: if etm.has_description() { Some(etm.take_description()) } else { None },
+                thumbnail: if etm.has_jpegThumbnail() { Some(etm.take_jpegThumbnail()) } else { None },
+            };
+            return Ok(TextWithPreview { text: etm.take_text(), preview });
         }
         if message.has_imageMessage() {
             let mut imsg = message.take_imageMessage();
@@ -365,6 +470,16 @@ impl ChatMessageContent {
                 filename: dmsg.take_fileName()
             });
         }
+        if message.has_stickerMessage() {
+            let mut smsg = message.take_stickerMessage();
+            return Ok(Sticker {
+                info: get_fileinfo!(smsg),
+                width: smsg.get_width(),
+                height: smsg.get_height(),
+                animated: smsg.get_isAnimated(),
+                thumbnail: smsg.take_pngThumbnail()
+            });
+        }
         if message.has_contactMessage() {
             let mut cmsg = message.take_contactMessage();
             return Ok(Contact {
@@ -380,6 +495,23 @@ impl ChatMessageContent {
                 });
             }
         }
+        if message.has_groupInviteMessage() {
+            let mut gmsg = message.take_groupInviteMessage();
+            let expiration = if gmsg.has_inviteExpiration() {
+                Some(parse_timestamp(gmsg.get_inviteExpiration())?)
+            }
+            else {
+                None
+            };
+            return Ok(GroupInvite {
+                jid: gmsg.get_groupJid().parse()?,
+                code: gmsg.take_inviteCode(),
+                expiration,
+                group_name: gmsg.take_groupName(),
+                thumbnail: gmsg.take_jpegThumbnail(),
+                caption: get_caption!(gmsg)
+            });
+        }
         if message.has_locationMessage() {
             let mut lmsg = message.take_locationMessage();
             let name = if lmsg.has_name() { Some(lmsg.take_name()) } else { None };
@@ -405,18 +537,35 @@ impl ChatMessageContent {
         }
         Ok(ChatMessageContent::Unimplemented(format!("{:?}", message)))
     }
-    pub fn into_proto(self) -> message_wire::Message {
+    /// Convert this content into its protobuf representation.
+    ///
+    /// This fails only for `Unimplemented`, which doesn't keep enough
+    /// information around to be re-encoded.
+    pub fn into_proto(self) -> Result<message_wire::Message> {
         let mut message = message_wire::Message::new();
         match self {
             ChatMessageContent::Text(text) => message.set_conversation(text),
+            ChatMessageContent::TextWithPreview { text, preview } => {
+                let mut extended_text_message = message_wire::ExtendedTextMessage::new();
+                extended_text_message.set_text(text);
+                extended_text_message.set_matchedText(preview.matched_text);
+                if let Some(canonical_url) = preview.canonical_url {
+                    extended_text_message.set_canonicalUrl(canonical_url);
+                }
+                if let Some(title) = preview.title {
+                    extended_text_message.set_title(title);
+                }
+                if let Some(description) = preview.description {
+                    extended_text_message.set_description(description);
+                }
+                if let Some(thumbnail) = preview.thumbnail {
+                    extended_text_message.set_jpegThumbnail(thumbnail);
+                }
+                message.set_extendedTextMessage(extended_text_message);
+            }
             ChatMessageContent::Image { info, height, width, thumbnail, caption } => {
                 let mut image_message = message_wire::ImageMessage::new();
-                image_message.set_url(info.url);
-                image_message.set_mimetype(info.mime);
-                image_message.set_fileEncSha256(info.enc_sha256);
-                image_message.set_fileSha256(info.sha256);
-                image_message.set_fileLength(info.size as u64);
-                image_message.set_mediaKey(info.key);
+                set_fileinfo!(image_message, info);
                 image_message.set_height(height);
                 image_message.set_width(width);
                 image_message.set_jpegThumbnail(thumbnail);
@@ -425,39 +574,110 @@ impl ChatMessageContent {
                 }
                 message.set_imageMessage(image_message);
             }
+            ChatMessageContent::Video { info, dur, caption } => {
+                let mut video_message = message_wire::VideoMessage::new();
+                set_fileinfo!(video_message, info);
+                video_message.set_seconds(dur.as_secs() as u32);
+                if let Some(caption) = caption {
+                    video_message.set_caption(caption);
+                }
+                message.set_videoMessage(video_message);
+            }
             ChatMessageContent::Document{ info, filename } => {
                 let mut document_message = message_wire::DocumentMessage::new();
-                document_message.set_url(info.url);
-                document_message.set_mimetype(info.mime);
-                document_message.set_fileEncSha256(info.enc_sha256);
-                document_message.set_fileSha256(info.sha256);
-                document_message.set_fileLength(info.size as u64);
-                document_message.set_mediaKey(info.key);
+                set_fileinfo!(document_message, info);
                 document_message.set_fileName(filename);
                 message.set_documentMessage(document_message);
             }
             ChatMessageContent::Audio {info, dur, ptt} => {
                 let mut audio_message = message_wire::AudioMessage::new();
-                audio_message.set_url(info.url);
-                audio_message.set_mimetype(info.mime);
-                audio_message.set_fileEncSha256(info.enc_sha256);
-                audio_message.set_fileSha256(info.sha256);
-                audio_message.set_fileLength(info.size as u64);
-                audio_message.set_mediaKey(info.key);
+                set_fileinfo!(audio_message, info);
                 audio_message.set_ptt(ptt);
                 audio_message.set_seconds(dur.as_secs() as u32);
                 //FIXME missing sidecar
                 message.set_audioMessage(audio_message);
             }
-            _ => unimplemented!()
+            ChatMessageContent::Sticker { info, width, height, animated, thumbnail } => {
+                let mut sticker_message = message_wire::StickerMessage::new();
+                set_fileinfo!(sticker_message, info);
+                sticker_message.set_width(width);
+                sticker_message.set_height(height);
+                sticker_message.set_isAnimated(animated);
+                sticker_message.set_pngThumbnail(thumbnail);
+                message.set_stickerMessage(sticker_message);
+            }
+            ChatMessageContent::Contact { display_name, vcard } => {
+                let mut contact_message = message_wire::ContactMessage::new();
+                contact_message.set_displayName(display_name);
+                contact_message.set_vcard(vcard);
+                message.set_contactMessage(contact_message);
+            }
+            ChatMessageContent::GroupInvite { jid, code, expiration, group_name, thumbnail, caption } => {
+                let mut group_invite_message = message_wire::GroupInviteMessage::new();
+                group_invite_message.set_groupJid(jid.to_string());
+                group_invite_message.set_inviteCode(code);
+                if let Some(expiration) = expiration {
+                    group_invite_message.set_inviteExpiration(expiration.and_utc().timestamp());
+                }
+                group_invite_message.set_groupName(group_name);
+                group_invite_message.set_jpegThumbnail(thumbnail);
+                if let Some(caption) = caption {
+                    group_invite_message.set_caption(caption);
+                }
+                message.set_groupInviteMessage(group_invite_message);
+            }
+            ChatMessageContent::Location { lat, long, name, address } => {
+                let mut location_message = message_wire::LocationMessage::new();
+                location_message.set_degreesLatitude(lat);
+                location_message.set_degreesLongitude(long);
+                if let Some(name) = name {
+                    location_message.set_name(name);
+                }
+                if let Some(address) = address {
+                    location_message.set_address(address);
+                }
+                message.set_locationMessage(location_message);
+            }
+            ChatMessageContent::LiveLocation { lat, long, accuracy, speed, heading, seq } => {
+                let mut live_location_message = message_wire::LiveLocationMessage::new();
+                live_location_message.set_degreesLatitude(lat);
+                live_location_message.set_degreesLongitude(long);
+                if let Some(accuracy) = accuracy {
+                    live_location_message.set_accuracyInMeters(accuracy);
+                }
+                if let Some(speed) = speed {
+                    live_location_message.set_speedInMps(speed);
+                }
+                if let Some(heading) = heading {
+                    live_location_message.set_degreesClockwiseFromMagneticNorth(heading);
+                }
+                if let Some(seq) = seq {
+                    live_location_message.set_sequenceNumber(seq);
+                }
+                message.set_liveLocationMessage(live_location_message);
+            }
+            ChatMessageContent::Redaction { mid } => {
+                // The rest of the key gets filled in by `ChatMessage::into_proto()`.
+                let mut key = message_wire::MessageKey::new();
+                key.set_id(mid.0);
+                let mut protocol_message = message_wire::ProtocolMessage::new();
+                protocol_message.set_key(key);
+                protocol_message.set_field_type(message_wire::ProtocolMessage_PROTOCOL_MESSAGE_TYPE::REVOKE);
+                message.set_protocolMessage(protocol_message);
+            }
+            ChatMessageContent::Unimplemented(_) => {
+                return Err(WaError::UnsupportedContent("unimplemented message type"));
+            }
         }
 
-        message
+        Ok(message)
     }
 }
 /// A message embedded in another.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct QuotedChatMessage {
+    /// The ID of the quoted message.
+    pub id: MessageId,
     /// The person who originally sent the quoted message.
     pub participant: Jid,
     /// The message contents.
@@ -465,43 +685,108 @@ pub struct QuotedChatMessage {
 }
 impl QuotedChatMessage {
     pub fn from_message(m: &mut message_wire::Message) -> Result<Option<Self>> {
-        if m.has_extendedTextMessage() {
-            return Ok(get_context_info!(m.mut_extendedTextMessage()));
-        }
-        if m.has_imageMessage() {
-            return Ok(get_context_info!(m.mut_imageMessage()));
-        }
-        if m.has_audioMessage() {
-            return Ok(get_context_info!(m.mut_audioMessage()));
-        }
-        if m.has_videoMessage() {
-            return Ok(get_context_info!(m.mut_videoMessage()));
-        }
-        if m.has_documentMessage() {
-            return Ok(get_context_info!(m.mut_documentMessage()));
+        match take_context_info(m) {
+            Some(ctx) => Self::from_context_info(ctx),
+            None => Ok(None)
         }
-        if m.has_contactMessage() {
-            return Ok(get_context_info!(m.mut_contactMessage()));
-        }
-        if m.has_locationMessage() {
-            return Ok(get_context_info!(m.mut_locationMessage()));
-        }
-        Ok(None)
     }
     pub fn from_context_info(mut ctx: message_wire::ContextInfo) -> Result<Option<Self>> {
         if !ctx.has_participant() || !ctx.has_quotedMessage() {
             return Ok(None);
         }
         let participant: Jid = ctx.take_participant().parse()?;
+        let id = MessageId(ctx.take_stanzaId());
         let content = ChatMessageContent::from_proto(ctx.take_quotedMessage())?;
-        Ok(Some(Self { participant, content }))
+        Ok(Some(Self { id, participant, content }))
+    }
+    fn into_context_info(self, ctx: &mut message_wire::ContextInfo) -> Result<()> {
+        ctx.set_stanzaId(self.id.0);
+        ctx.set_participant(self.participant.to_message_jid());
+        ctx.set_quotedMessage(self.content.into_proto()?);
+        Ok(())
+    }
+}
+/// Take the `ContextInfo` out of whichever part of the message has it.
+fn take_context_info(m: &mut message_wire::Message) -> Option<message_wire::ContextInfo> {
+    if m.has_extendedTextMessage() {
+        return get_context_info!(m.mut_extendedTextMessage());
+    }
+    if m.has_imageMessage() {
+        return get_context_info!(m.mut_imageMessage());
+    }
+    if m.has_audioMessage() {
+        return get_context_info!(m.mut_audioMessage());
+    }
+    if m.has_videoMessage() {
+        return get_context_info!(m.mut_videoMessage());
+    }
+    if m.has_documentMessage() {
+        return get_context_info!(m.mut_documentMessage());
+    }
+    if m.has_contactMessage() {
+        return get_context_info!(m.mut_contactMessage());
+    }
+    if m.has_locationMessage() {
+        return get_context_info!(m.mut_locationMessage());
+    }
+    if m.has_liveLocationMessage() {
+        return get_context_info!(m.mut_liveLocationMessage());
+    }
+    if m.has_stickerMessage() {
+        return get_context_info!(m.mut_stickerMessage());
+    }
+    if m.has_groupInviteMessage() {
+        return get_context_info!(m.mut_groupInviteMessage());
+    }
+    None
+}
+/// Attach a `ContextInfo` to the message, turning plain text into an
+/// `ExtendedTextMessage` (since `conversation` can't carry one).
+fn set_context_info(m: &mut message_wire::Message, ctx: message_wire::ContextInfo) {
+    if m.has_conversation() {
+        let mut etm = message_wire::ExtendedTextMessage::new();
+        etm.set_text(m.take_conversation());
+        m.set_extendedTextMessage(etm);
+    }
+    if m.has_extendedTextMessage() {
+        m.mut_extendedTextMessage().set_contextInfo(ctx);
+    }
+    else if m.has_imageMessage() {
+        m.mut_imageMessage().set_contextInfo(ctx);
+    }
+    else if m.has_audioMessage() {
+        m.mut_audioMessage().set_contextInfo(ctx);
+    }
+    else if m.has_videoMessage() {
+        m.mut_videoMessage().set_contextInfo(ctx);
+    }
+    else if m.has_documentMessage() {
+        m.mut_documentMessage().set_contextInfo(ctx);
+    }
+    else if m.has_contactMessage() {
+        m.mut_contactMessage().set_contextInfo(ctx);
+    }
+    else if m.has_locationMessage() {
+        m.mut_locationMessage().set_contextInfo(ctx);
+    }
+    else if m.has_liveLocationMessage() {
+        m.mut_liveLocationMessage().set_contextInfo(ctx);
+    }
+    else if m.has_stickerMessage() {
+        m.mut_stickerMessage().set_contextInfo(ctx);
+    }
+    else if m.has_groupInviteMessage() {
+        m.mut_groupInviteMessage().set_contextInfo(ctx);
+    }
+    else {
+        warn!("Dropping context info for a message type that can't carry it");
     }
 }
 
 pub use crate::message_wire::WebMessageInfo_WEB_MESSAGE_INFO_STUBTYPE as MessageStubType;
 
 /// A WhatsApp message.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ChatMessage {
     /// The direction of this message - i.e. who we're sending it to, or receiving it from.
     pub direction: Direction,
@@ -514,7 +799,9 @@ pub struct ChatMessage {
     /// The message this message is in reply to (or quoting), if any.
     pub quoted: Option<QuotedChatMessage>,
     /// If this message has a stub type, that stub type.
-    pub stub_type: Option<MessageStubType>
+    pub stub_type: Option<MessageStubType>,
+    /// Users mentioned (i.e. @-ed) in this message.
+    pub mentions: Vec<Jid>
 }
 
 impl ChatMessage {
@@ -527,9 +814,17 @@ impl ChatMessage {
             direction: Direction::Sending(to),
             id: message_id,
             quoted: None,
-            stub_type: None
+            stub_type: None,
+            mentions: vec![]
         }
     }
+    /// Create a message revoking (i.e. 'deleting for everyone') a message we
+    /// sent earlier to the chat with the given JID.
+    ///
+    /// This works for both individual and group chats.
+    pub fn revoke(jid: Jid, mid: MessageId) -> Self {
+        Self::new(jid, ChatMessageContent::Redaction { mid })
+    }
     pub(crate) fn from_proto_binary(content: &[u8]) -> Result<ChatMessage> {
         let webmessage = protobuf::parse_from_bytes::<message_wire::WebMessageInfo>(content).map_err(|_| "Invalid Protobuf chatmessage")?;
         ChatMessage::from_proto(webmessage)
@@ -539,7 +834,15 @@ impl ChatMessage {
     pub(crate) fn from_proto(mut webmessage: message_wire::WebMessageInfo) -> Result<ChatMessage> {
         debug!("Processing WebMessageInfo: {:?}", &webmessage);
         let mut msg = webmessage.take_message();
-        let quoted = QuotedChatMessage::from_message(&mut msg)?;
+        let (quoted, mentions) = match take_context_info(&mut msg) {
+            Some(mut ctx) => {
+                let mentions = ctx.take_mentionedJid().into_iter()
+                    .map(|jid| jid.parse())
+                    .collect::<Result<Vec<Jid>>>()?;
+                (QuotedChatMessage::from_context_info(ctx)?, mentions)
+            },
+            None => (None, vec![])
+        };
         let stub_type = if webmessage.has_messageStubType() {
             Some(webmessage.get_messageStubType())
         }
@@ -549,39 +852,75 @@ impl ChatMessage {
         Ok(ChatMessage {
             id: MessageId(webmessage.mut_key().take_id()),
             direction: Direction::parse(&mut webmessage)?,
-            time: NaiveDateTime::from_timestamp(webmessage.get_messageTimestamp() as i64, 0),
+            time: parse_timestamp(webmessage.get_messageTimestamp() as i64)?,
             content: ChatMessageContent::from_proto(msg)?,
-            quoted, stub_type
+            quoted, stub_type, mentions
         })
     }
 
-    pub(crate) fn into_proto_binary(self) -> Vec<u8> {
-        let webmessage = self.into_proto();
-        webmessage.write_to_bytes().unwrap()
+    pub(crate) fn into_proto_binary(self) -> Result<Vec<u8>> {
+        let webmessage = self.into_proto()?;
+        Ok(webmessage.write_to_bytes()?)
     }
 
-    pub(crate) fn into_proto(self) -> message_wire::WebMessageInfo {
+    /// Convert this message into its protobuf representation.
+    ///
+    /// Messages in either direction can be converted, so received messages
+    /// can be archived and later parsed again with `from_proto()`.
+    pub(crate) fn into_proto(self) -> Result<message_wire::WebMessageInfo> {
         let mut webmessage = message_wire::WebMessageInfo::new();
         let mut key = message_wire::MessageKey::new();
 
         key.set_id(self.id.0);
+        let sending = self.direction.is_sending();
         match self.direction {
             Direction::Sending(jid) => {
                 key.set_remoteJid(jid.to_message_jid());
                 key.set_fromMe(true);
             }
-            Direction::Receiving(_) => unimplemented!()
+            Direction::Receiving(Peer::Individual(jid)) => {
+                key.set_remoteJid(jid.to_message_jid());
+                key.set_fromMe(false);
+            }
+            Direction::Receiving(Peer::Group { group, participant }) => {
+                key.set_remoteJid(group.to_message_jid());
+                key.set_fromMe(false);
+                webmessage.set_participant(participant.to_message_jid());
+            }
         }
-        webmessage.set_key(key);
 
         webmessage.set_messageTimestamp(self.time.timestamp() as u64);
 
-        webmessage.set_message(self.content.into_proto());
+        let mut message = self.content.into_proto()?;
+        if message.has_protocolMessage() {
+            // The revoked message is from the same sender, in the same chat.
+            let revoked = message.mut_protocolMessage().mut_key();
+            revoked.set_remoteJid(key.get_remoteJid().to_owned());
+            revoked.set_fromMe(key.get_fromMe());
+            if webmessage.has_participant() {
+                revoked.set_participant(webmessage.get_participant().to_owned());
+            }
+        }
+        if self.quoted.is_some() || !self.mentions.is_empty() {
+            let mut ctx = message_wire::ContextInfo::new();
+            if let Some(quoted) = self.quoted {
+                quoted.into_context_info(&mut ctx)?;
+            }
+            ctx.set_mentionedJid(self.mentions.iter().map(|jid| jid.to_message_jid()).collect::<Vec<_>>().into());
+            set_context_info(&mut message, ctx);
+        }
+        webmessage.set_key(key);
+        webmessage.set_message(message);
 
-        webmessage.set_status(message_wire::WebMessageInfo_WEB_MESSAGE_INFO_STATUS::PENDING);
+        if let Some(stub_type) = self.stub_type {
+            webmessage.set_messageStubType(stub_type);
+        }
+        if sending {
+            webmessage.set_status(message_wire::WebMessageInfo_WEB_MESSAGE_INFO_STATUS::PENDING);
+        }
         debug!("Building WebMessageInfo: {:?}", &webmessage);
 
-        webmessage
+        Ok(webmessage)
     }
 }
 
@@ -590,3 +929,196 @@ impl Jid {
         self.id.to_string() + if self.is_group { "@g.us" } else { "@s.whatsapp.net" }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use protobuf::ProtobufEnum;
+    use futures::SinkExt;
+    use crate::message_wire::{WebMessageInfo, ProtocolMessage_PROTOCOL_MESSAGE_TYPE};
+    use crate::testing::{self, connect_scripted, next_event, NodeContent};
+    use crate::event::WaEvent;
+    use crate::req::WaRequest;
+
+    fn jid() -> impl Strategy<Value = Jid> {
+        ("[0-9]{5,15}", any::<bool>()).prop_map(|(id, is_group)| Jid { id, is_group })
+    }
+
+    fn file_info() -> impl Strategy<Value = FileInfo> {
+        ("\\PC*", "\\PC*", any::<Vec<u8>>(), any::<Vec<u8>>(), any::<u32>(), any::<Vec<u8>>())
+            .prop_map(|(url, mime, sha256, enc_sha256, size, key)| {
+                FileInfo { url, mime, sha256, enc_sha256, size: size as usize, key }
+            })
+    }
+
+    fn duration() -> impl Strategy<Value = Duration> {
+        any::<u32>().prop_map(|s| Duration::from_secs(u64::from(s)))
+    }
+
+    fn content() -> impl Strategy<Value = ChatMessageContent> {
+        use self::ChatMessageContent::*;
+
+        prop_oneof![
+            "\\PC*".prop_map(Text),
+            ("\\PC*", "\\PC*", proptest::option::of("\\PC*"), proptest::option::of("\\PC*"),
+             proptest::option::of("\\PC*"), proptest::option::of(any::<Vec<u8>>()))
+                .prop_map(|(text, matched_text, canonical_url, title, description, thumbnail)| TextWithPreview {
+                    text,
+                    preview: LinkPreview { matched_text, canonical_url, title, description, thumbnail }
+                }),
+            (file_info(), any::<u32>(), any::<u32>(), any::<Vec<u8>>(), proptest::option::of("\\PC*"))
+                .prop_map(|(info, height, width, thumbnail, caption)| Image { info, height, width, thumbnail, caption }),
+            (file_info(), duration(), any::<bool>())
+                .prop_map(|(info, dur, ptt)| Audio { info, dur, ptt }),
+            (file_info(), duration(), proptest::option::of("\\PC*"))
+                .prop_map(|(info, dur, caption)| Video { info, dur, caption }),
+            (file_info(), "\\PC*")
+                .prop_map(|(info, filename)| Document { info, filename }),
+            (file_info(), any::<u32>(), any::<u32>(), any::<bool>(), any::<Vec<u8>>())
+                .prop_map(|(info, width, height, animated, thumbnail)| Sticker { info, width, height, animated, thumbnail }),
+            ("\\PC*", "\\PC*")
+                .prop_map(|(display_name, vcard)| Contact { display_name, vcard }),
+            (jid(), "[0-9A-Za-z]{22}", proptest::option::of(0..i64::from(i32::max_value())), "\\PC*",
+             any::<Vec<u8>>(), proptest::option::of("\\PC*"))
+                .prop_map(|(jid, code, expiration, group_name, thumbnail, caption)| GroupInvite {
+                    jid, code, group_name, thumbnail, caption,
+                    expiration: expiration.map(|e| parse_timestamp(e).unwrap())
+                }),
+            (-90.0..90.0f64, -180.0..180.0f64, proptest::option::of("\\PC*"), proptest::option::of("\\PC*"))
+                .prop_map(|(lat, long, name, address)| Location { lat, long, name, address }),
+            (-90.0..90.0f64, -180.0..180.0f64, proptest::option::of(any::<u32>()),
+             proptest::option::of(0.0..100.0f32), proptest::option::of(0..360u32), proptest::option::of(any::<i64>()))
+                .prop_map(|(lat, long, accuracy, speed, heading, seq)| LiveLocation { lat, long, accuracy, speed, heading, seq }),
+            "[0-9A-F]{20}".prop_map(|id| Redaction { mid: MessageId(id) }),
+        ]
+    }
+
+    fn direction() -> impl Strategy<Value = Direction> {
+        prop_oneof![
+            jid().prop_map(Direction::Sending),
+            jid().prop_map(|jid| Direction::Receiving(Peer::Individual(jid))),
+            (jid(), jid()).prop_map(|(group, participant)| Direction::Receiving(Peer::Group { group, participant })),
+        ]
+    }
+
+    fn chat_message() -> impl Strategy<Value = ChatMessage> {
+        let quoted = ("[0-9A-F]{20}", jid(), content())
+            .prop_map(|(id, participant, content)| QuotedChatMessage { id: MessageId(id), participant, content });
+        let stub_type = proptest::sample::select(MessageStubType::values().to_vec());
+        ("[0-9A-F]{20}", direction(), 0..i64::from(i32::max_value()), content(),
+         proptest::option::of(quoted), proptest::option::of(stub_type), proptest::collection::vec(jid(), 0..3))
+            .prop_map(|(id, direction, time, content, quoted, stub_type, mentions)| {
+                // Redactions have nowhere to put a `ContextInfo`.
+                let (quoted, mentions) = match content {
+                    ChatMessageContent::Redaction { .. } => (None, vec![]),
+                    _ => (quoted, mentions)
+                };
+                ChatMessage {
+                    direction,
+                    time: parse_timestamp(time).unwrap(),
+                    id: MessageId(id),
+                    content, quoted, stub_type, mentions
+                }
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_content_round_trip(c in content()) {
+            let proto = c.clone().into_proto().unwrap();
+            prop_assert_eq!(ChatMessageContent::from_proto(proto).unwrap(), c);
+        }
+
+        #[test]
+        fn test_message_round_trip(m in chat_message()) {
+            let bin = m.clone().into_proto_binary().unwrap();
+            prop_assert_eq!(ChatMessage::from_proto_binary(&bin).unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn test_unimplemented_content() {
+        let c = ChatMessageContent::Unimplemented("???".into());
+        match c.into_proto() {
+            Err(WaError::UnsupportedContent(_)) => {},
+            _ => panic!("expected UnsupportedContent")
+        }
+    }
+    #[test]
+    fn test_timestamp_out_of_range() {
+        let mut msg = ChatMessage::new(testing::jid("447700900001@c.us"), ChatMessageContent::Text("hi".into()));
+        msg.time = parse_timestamp(1500000000).unwrap();
+        let mut proto = msg.into_proto().unwrap();
+        proto.set_messageTimestamp(i64::max_value() as u64);
+        match ChatMessage::from_proto(proto) {
+            Err(WaError::InvalidTimestamp(_)) => {},
+            _ => panic!("expected InvalidTimestamp")
+        }
+    }
+    #[tokio::test]
+    async fn test_revoke_message() {
+        let group = testing::jid("447700900000-1234567890@g.us");
+        let revoked = MessageId::generate();
+        let (mut conn, sent_rx) = connect_scripted(|mut client| async move {
+            let (tag, node) = client.recv_node().await.unwrap();
+            client.send_json(&tag, object!{ "status" => 200, "t" => 1500000000 }).await.unwrap();
+            (client, node)
+        }).await;
+        conn.send(WaRequest::RevokeMessage { jid: group.clone(), mid: revoked.clone() }).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::MessageAck(_) => {},
+            _ => panic!("expected MessageAck")
+        }
+
+        let node = sent_rx.await.unwrap();
+        let msg = match node.content {
+            NodeContent::List(ref children) => match children[0].content {
+                NodeContent::Binary(ref b) => protobuf::parse_from_bytes::<WebMessageInfo>(b).unwrap(),
+                _ => panic!("message node has no binary content")
+            },
+            _ => panic!("action node has no children")
+        };
+        let pmsg = msg.get_message().get_protocolMessage();
+        assert_eq!(pmsg.get_field_type(), ProtocolMessage_PROTOCOL_MESSAGE_TYPE::REVOKE);
+        assert_eq!(pmsg.get_key().get_id(), revoked.0);
+        assert_eq!(pmsg.get_key().get_remoteJid(), group.to_message_jid());
+        assert!(pmsg.get_key().get_fromMe());
+    }
+    #[tokio::test]
+    async fn test_quoted_reply_with_mentions() {
+        let group = testing::jid("447700900000-1234567890@g.us");
+        let them = testing::jid("447700900001@c.us");
+        let quoted_id = MessageId::generate();
+        let (server_group, server_them, server_quoted_id) = (group.clone(), them.clone(), quoted_id.clone());
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let mut msg = ChatMessage::new(server_group.clone(), ChatMessageContent::Text("@447700900001 yes".into()));
+            msg.direction = Direction::Receiving(Peer::Group { group: server_group, participant: server_them.clone() });
+            msg.quoted = Some(QuotedChatMessage {
+                id: server_quoted_id,
+                participant: server_them.clone(),
+                content: ChatMessageContent::Text("lunch?".into())
+            });
+            msg.mentions = vec![server_them];
+            client.send_node(testing::action("relay", vec![testing::message(msg)])).await.unwrap();
+            (client, ())
+        }).await;
+        match next_event(&mut conn).await {
+            WaEvent::Message { msg, .. } => {
+                match msg.content {
+                    ChatMessageContent::Text(t) => assert_eq!(t, "@447700900001 yes"),
+                    _ => panic!("wrong message content")
+                }
+                let quoted = msg.quoted.expect("no quoted message");
+                assert_eq!(quoted.id, quoted_id);
+                assert_eq!(quoted.participant, them);
+                match quoted.content {
+                    ChatMessageContent::Text(t) => assert_eq!(t, "lunch?"),
+                    _ => panic!("wrong quoted content")
+                }
+                assert_eq!(msg.mentions, vec![them]);
+            },
+            _ => panic!("expected Message")
+        }
+    }
+}
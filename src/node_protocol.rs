@@ -1,13 +1,18 @@
 use std::collections::HashMap;
+use std::borrow::Cow;
 
 use crate::Contact;
 use crate::Jid;
 use crate::Chat;
 use crate::ChatAction;
+use crate::LastMessage;
 use crate::PresenceStatus;
 use crate::GroupParticipantsChange;
+use crate::GroupSetting;
+use crate::ProfilePicture;
 use crate::node_wire::{Node, NodeContent, IntoCow};
-use crate::message::{ChatMessage, MessageAck, MessageAckLevel, Peer, MessageId};
+use crate::parse_timestamp;
+use crate::message::{ChatMessage, MessageAck, MessageAckLevel, MessageReceipt, SearchResults, Peer, MessageId};
 use crate::errors::*;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -22,9 +27,23 @@ pub enum MessageEventType {
 pub enum GroupCommand {
     Create(String),
     ParticipantsChange(Jid, GroupParticipantsChange),
-    //TODO
-    #[allow(dead_code)]
-    Leave(Jid)
+    Leave(Jid),
+    Subject(Jid, String),
+    Description { jid: Jid, id: String, prev: Option<String>, description: Option<String> },
+    Setting(Jid, GroupSetting, bool),
+    Delete(Jid)
+}
+
+impl GroupCommand {
+    /// The group this command applies to, if it exists yet.
+    pub fn jid(&self) -> Option<&Jid> {
+        use self::GroupCommand::*;
+        match *self {
+            Create(_) => None,
+            ParticipantsChange(ref jid, _) | Leave(ref jid) | Subject(ref jid, _) |
+            Description { ref jid, .. } | Setting(ref jid, _, _) | Delete(ref jid) => Some(jid)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -38,6 +57,9 @@ pub enum AppEvent {
     ContactAddChange(Contact),
 
     ChatAction(Jid, ChatAction),
+    /// Messages in a chat were starred or unstarred. Each message is
+    /// given by its ID, and whether we sent it.
+    StarMessages { jid: Jid, starred: bool, messages: Vec<(MessageId, bool)> },
     //App only
     Battery(u8),
 
@@ -56,12 +78,20 @@ pub enum AppEvent {
     //Client only
     NotifyChange(String),
     //Client only
-    BlockProfile { unblock: bool, jid: Jid }
+    BlockProfile { unblock: bool, jid: Jid },
+    //Client only
+    SetPicture { jid: Jid, id: String, picture: Option<ProfilePicture> }
 }
 
 #[derive(Debug)]
 pub enum Query {
-    MessagesBefore { jid: Jid, id: String, count: u16 }
+    MessagesBefore { jid: Jid, id: String, from_me: bool, count: u16 },
+    MessagesAfter { jid: Jid, id: String, from_me: bool, count: u16 },
+    MessagesAround { jid: Jid, id: String, from_me: bool, count: u16 },
+    MessagesLast { jid: Jid, count: u16 },
+    MessageInfo { jid: Jid, id: String },
+    Starred { jid: Option<Jid>, count: u16, before: Option<(MessageId, bool)> },
+    Search { query: String, jid: Option<Jid>, page: u32, count: u16 }
 }
 
 #[derive(Debug)]
@@ -97,10 +127,11 @@ impl AppMessage {
             }
             "read" => {
                 let jid = node.take_attribute("jid")?.into_jid()?;
+                let last = LastMessage::from_node(&mut node);
                 Ok(Some(AppEvent::ChatAction(jid, if node.take_attribute("type").ok().map_or(true, |typ| typ.as_str() != "false") {
-                    ChatAction::Read
+                    ChatAction::Read(last)
                 } else {
-                    ChatAction::Unread
+                    ChatAction::Unread(last)
                 })))
             }
             "user" => {
@@ -113,6 +144,22 @@ impl AppMessage {
             }
             "chat" => {
                 let jid = node.take_attribute("jid")?.into_jid()?;
+                let starred = match node.get_attribute("type")?.as_str() {
+                    "star" => Some(true),
+                    "unstar" => Some(false),
+                    _ => None
+                };
+                if let Some(starred) = starred {
+                    let mut messages = vec![];
+                    if let NodeContent::List(items) = node.content {
+                        for mut item in items {
+                            let id = MessageId(item.take_attribute("index")?.into_string());
+                            let from_me = item.take_attribute("owner").ok().map_or(false, |owner| owner.as_str() == "true");
+                            messages.push((id, from_me));
+                        }
+                    }
+                    return Ok(Some(AppEvent::StarMessages { jid, starred, messages }));
+                }
                 let action = ChatAction::from_node(&mut node)?;
                 Ok(Some(AppEvent::ChatAction(jid, action)))
             }
@@ -180,16 +227,16 @@ impl AppMessage {
             x => bail_untyped!{ "invalid app message type {}", x}
         }
     }
-    pub fn serialize(self, epoch: u32) -> Node {
+    pub fn serialize(self, epoch: u32) -> Result<Node> {
         let mut attributes = HashMap::new();
         attributes.insert("epoch".cow(), NodeContent::String(epoch.to_string().cow()));
 
-        match self {
+        Ok(match self {
             AppMessage::MessagesEvents(typ, events) => {
                 attributes.insert("type".cow(), NodeContent::Token(typ.unwrap().into_node()));
                 Node::new("action", attributes, NodeContent::List(
                     events.into_iter().map(|event| {
-                        match event {
+                        Ok(match event {
                             AppEvent::MessageRead { id, peer } => {
                                 let mut attributes = HashMap::new();
                                 attributes.insert("index".cow(), NodeContent::String(id.0.cow()));
@@ -226,10 +273,15 @@ impl AppMessage {
                             }
 
                             AppEvent::Message(message) => {
-                                Node::new("message", HashMap::new(), NodeContent::Binary(message.into_proto_binary()))
+                                Node::new("message", HashMap::new(), NodeContent::Binary(message.into_proto_binary()?))
                             }
                             AppEvent::GroupCommand { inducer, id, participants, command } => {
                                 let mut attributes = HashMap::new();
+                                let mut children: Vec<Node> = participants.into_iter().map(|jid| {
+                                    let mut attributes = HashMap::new();
+                                    attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                    Node::new("participant", attributes, NodeContent::None)
+                                }).collect();
                                 match command {
                                     GroupCommand::Create(subject) => {
                                         attributes.insert("subject".cow(), NodeContent::String(subject.cow()));
@@ -243,18 +295,40 @@ impl AppMessage {
                                         attributes.insert("type".cow(), NodeContent::Token("leave"));
                                         attributes.insert("jid".cow(), NodeContent::Jid(jid));
                                     }
+                                    GroupCommand::Subject(jid, subject) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("subject"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                        attributes.insert("subject".cow(), NodeContent::String(subject.cow()));
+                                    }
+                                    GroupCommand::Description { jid, id, prev, description } => {
+                                        attributes.insert("type".cow(), NodeContent::Token("description"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                        let mut desc = Node::new_empty("description");
+                                        desc.set_attribute("id", NodeContent::String(id.cow()));
+                                        if let Some(prev) = prev {
+                                            desc.set_attribute("prev", NodeContent::String(prev.cow()));
+                                        }
+                                        match description {
+                                            Some(d) => desc.content = NodeContent::Binary(d.into_bytes()),
+                                            None => desc.set_attribute("delete", NodeContent::Token("true"))
+                                        }
+                                        children.push(desc);
+                                    }
+                                    GroupCommand::Setting(jid, setting, value) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("prop"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                        let mut prop = Node::new_empty(setting.into_node());
+                                        prop.set_attribute("value", NodeContent::Token(if value { "true" } else { "false" }));
+                                        children.push(prop);
+                                    }
+                                    GroupCommand::Delete(jid) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("delete"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                    }
                                 }
                                 attributes.insert("author".cow(), NodeContent::Jid(inducer));
                                 attributes.insert("id".cow(), NodeContent::String(id.cow()));
-                                Node::new(
-                                    "group",
-                                    attributes,
-                                    NodeContent::List(participants.into_iter().map(|jid| {
-                                        let mut attributes = HashMap::new();
-                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
-                                        Node::new("participant", attributes, NodeContent::None)
-                                    }).collect())
-                                )
+                                Node::new("group", attributes, NodeContent::List(children))
                             }
                             AppEvent::PresenceChange(status, jid) => {
                                 let mut attributes = HashMap::new();
@@ -264,38 +338,20 @@ impl AppMessage {
                                 }
                                 Node::new("presence", attributes, NodeContent::None)
                             }
-                            AppEvent::ChatAction(jid, action) => {
+                            AppEvent::ChatAction(jid, action) => action.into_node(jid)?,
+                            AppEvent::StarMessages { jid, starred, messages } => {
                                 let mut attributes = HashMap::new();
                                 attributes.insert("jid".cow(), NodeContent::Jid(jid));
-                                match action {
-                                    ChatAction::Pin(time) => {
-                                        attributes.insert("type".cow(), NodeContent::String("pin".cow()));
-                                        attributes.insert("pin".cow(), NodeContent::String(time.to_string().cow()));
-                                    }
-                                    //Fixme
-                                    ChatAction::Unpin => {
-                                        attributes.insert("type".cow(), NodeContent::String("pin".cow()));
-                                        //attributes.insert("previous".to_string(), NodeContent::String(time.to_string()));
-                                    }
-                                    ChatAction::Mute(time) => {
-                                        attributes.insert("type".cow(), NodeContent::Token("mute"));
-                                        attributes.insert("mute".cow(), NodeContent::String(time.to_string().cow()));
-                                    }
-                                    //Fixme
-                                    ChatAction::Unmute => {
-                                        attributes.insert("type".cow(), NodeContent::Token("mute"));
-                                    }
-                                    ChatAction::Archive => {
-                                        attributes.insert("type".cow(), NodeContent::Token("archive"));
-                                    }
-                                    ChatAction::Unarchive => {
-                                        attributes.insert("type".cow(), NodeContent::Token("unarchive"));
-                                    }
-
-                                    _ => unimplemented!()
-                                }
-
-                                Node::new("chat", attributes, NodeContent::None)
+                                attributes.insert("type".cow(), NodeContent::Token(if starred { "star" } else { "unstar" }));
+                                let items = messages.into_iter()
+                                    .map(|(id, from_me)| {
+                                        let mut item = Node::new_empty("item");
+                                        item.set_attribute("index", NodeContent::String(id.0.cow()));
+                                        item.set_attribute("owner", NodeContent::Token(if from_me { "true" } else { "false" }));
+                                        item
+                                    })
+                                    .collect();
+                                Node::new("chat", attributes, NodeContent::List(items))
                             }
                             AppEvent::StatusChange(status) => {
                                 Node::new("status", HashMap::new(), NodeContent::String(status.cow()))
@@ -319,33 +375,97 @@ impl AppMessage {
                                     NodeContent::List(vec![user])
                                 )
                             }
-                            _ => unimplemented!()
-                        }
-                    }).collect())
+                            AppEvent::SetPicture { jid, id, picture } => {
+                                let mut attributes = HashMap::new();
+                                attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                attributes.insert("id".cow(), NodeContent::String(id.cow()));
+                                match picture {
+                                    Some(ProfilePicture { image, preview }) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("set"));
+                                        Node::new("picture", attributes, NodeContent::List(vec![
+                                            Node::new("image", HashMap::new(), NodeContent::Binary(image)),
+                                            Node::new("preview", HashMap::new(), NodeContent::Binary(preview))
+                                        ]))
+                                    },
+                                    None => {
+                                        attributes.insert("type".cow(), NodeContent::Token("delete"));
+                                        Node::new("picture", attributes, NodeContent::None)
+                                    }
+                                }
+                            }
+                            _ => return Err(WaError::UnsupportedAction("event can only be received"))
+                        })
+                    }).collect::<Result<_>>()?)
                 )
             }
             AppMessage::Query(query) => {
                 match query {
-                    Query::MessagesBefore { jid, id, count } => {
+                    Query::MessagesBefore { jid, id, from_me, count } => history_query(NodeContent::Token("before"), jid, Some((id, from_me)), count),
+                    Query::MessagesAfter { jid, id, from_me, count } => history_query(NodeContent::Token("after"), jid, Some((id, from_me)), count),
+                    Query::MessagesAround { jid, id, from_me, count } => history_query(NodeContent::String("around".cow()), jid, Some((id, from_me)), count),
+                    Query::MessagesLast { jid, count } => history_query(NodeContent::Token("last"), jid, None, count),
+                    Query::Starred { jid, count, before } => {
                         let mut node = Node::new_empty("query");
-                        node.set_attribute("type", NodeContent::Token("message"));
-                        node.set_attribute("kind", NodeContent::Token("before"));
-                        node.set_attribute("jid", NodeContent::Jid(jid));
+                        node.set_attribute("type", NodeContent::Token("star"));
                         node.set_attribute("count", NodeContent::String(count.to_string().cow()));
+                        if let Some(jid) = jid {
+                            node.set_attribute("jid", NodeContent::Jid(jid));
+                        }
+                        if let Some((id, from_me)) = before {
+                            node.set_attribute("index", NodeContent::String(id.0.cow()));
+                            node.set_attribute("owner", NodeContent::Token(if from_me { "true" } else { "false" }));
+                        }
+                        node
+                    }
+                    Query::Search { query, jid, page, count } => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("search"));
+                        node.set_attribute("search", NodeContent::String(query.cow()));
+                        node.set_attribute("page", NodeContent::String(page.to_string().cow()));
+                        node.set_attribute("count", NodeContent::String(count.to_string().cow()));
+                        if let Some(jid) = jid {
+                            node.set_attribute("jid", NodeContent::Jid(jid));
+                        }
+                        node
+                    }
+                    Query::MessageInfo { jid, id } => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("message_info"));
                         node.set_attribute("index", NodeContent::String(id.cow()));
-                        node.set_attribute("owner", NodeContent::Token("false"));
+                        node.set_attribute("jid", NodeContent::Jid(jid));
                         node
                     }
                 }
             }
-            _ => unreachable!()
-        }
+            AppMessage::Contacts(_) | AppMessage::Chats(_) => {
+                return Err(WaError::UnsupportedAction("message can only be received"));
+            }
+        })
+    }
+}
+
+/// Build a history query, starting from the given message (its ID, and
+/// whether we sent it) if there is one.
+fn history_query(kind: NodeContent, jid: Jid, index: Option<(String, bool)>, count: u16) -> Node {
+    let mut node = Node::new_empty("query");
+    node.set_attribute("type", NodeContent::Token("message"));
+    node.set_attribute("kind", kind);
+    node.set_attribute("jid", NodeContent::Jid(jid));
+    node.set_attribute("count", NodeContent::String(count.to_string().cow()));
+    if let Some((id, from_me)) = index {
+        node.set_attribute("index", NodeContent::String(id.cow()));
+        node.set_attribute("owner", NodeContent::Token(if from_me { "true" } else { "false" }));
     }
+    node
 }
 
 pub fn parse_message_response(root_node: Node) -> Result<Vec<ChatMessage>> {
-    if root_node.desc() == "response" && root_node.get_attribute("type").ok().map_or(false, |typ| typ.as_str() == "message") {
-        if let NodeContent::List(nodes) = root_node.content {
+    // Starred message queries and searches get the same response, but with a different type.
+    if root_node.desc() == "response" && root_node.get_attribute("type").ok().map_or(false, |typ| ["message", "star", "search"].contains(&typ.as_str())) {
+        if let NodeContent::None = root_node.content {
+            // No (more) messages.
+            Ok(vec![])
+        } else if let NodeContent::List(nodes) = root_node.content {
             let mut messages = Vec::with_capacity(nodes.len());
             for node in nodes {
                 if let NodeContent::Binary(ref content) = node.content {
@@ -363,6 +483,57 @@ pub fn parse_message_response(root_node: Node) -> Result<Vec<ChatMessage>> {
     }
 }
 
+pub fn parse_search_response(root_node: Node) -> Result<SearchResults> {
+    let last_page = root_node.get_attribute("last").ok().map_or(true, |last| last.as_str() == "true");
+    Ok(SearchResults {
+        messages: parse_message_response(root_node)?,
+        last_page
+    })
+}
+
+/// Parse the response to a message info query, returning the furthest
+/// each recipient has got with the message.
+pub fn parse_message_info_response(root_node: Node) -> Result<Vec<MessageReceipt>> {
+    if root_node.desc() != "response" || root_node.get_attribute("type").ok().map_or(true, |typ| typ.as_str() != "message_info") {
+        bail_untyped!{ "invalid response" }
+    }
+    let mut receipts: Vec<MessageReceipt> = vec![];
+    let lists = match root_node.content {
+        NodeContent::List(lists) => lists,
+        _ => return Ok(receipts)
+    };
+    for list in lists {
+        let level = match list.desc() {
+            "delivery" => MessageAckLevel::Received,
+            "read" => MessageAckLevel::Read,
+            "played" => MessageAckLevel::Played,
+            x => {
+                warn!("Unknown message info list {}", x);
+                continue;
+            }
+        };
+        let users = match list.content {
+            NodeContent::List(users) => users,
+            _ => continue
+        };
+        for mut user in users {
+            let jid = user.take_attribute("jid")?.into_jid()?;
+            let time = user.take_attribute("t").ok()
+                .and_then(|t| t.into_string().parse().ok())
+                .map(parse_timestamp)
+                .transpose()?;
+            match receipts.iter_mut().find(|r| r.jid == jid) {
+                Some(r) => if level as u8 > r.level as u8 {
+                    r.level = level;
+                    r.time = time;
+                },
+                None => receipts.push(MessageReceipt { jid, level, time })
+            }
+        }
+    }
+    Ok(receipts)
+}
+
 impl Contact {
     fn parse_node(node: &mut Node) -> Result<Contact> {
         Ok(Contact {
@@ -432,25 +603,94 @@ impl MessageEventType {
 }
 
 impl ChatAction {
+    fn into_node(self, jid: Jid) -> Result<Node> {
+        let mut attributes = HashMap::new();
+        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+        let desc = match self {
+            ChatAction::Read(last) => {
+                let last = last.ok_or(WaError::UnsupportedAction("marking a chat read needs its last message"))?;
+                attributes.insert("count".cow(), NodeContent::String("1".cow()));
+                last.into_attributes(&mut attributes);
+                "read"
+            }
+            ChatAction::Unread(last) => {
+                let last = last.ok_or(WaError::UnsupportedAction("marking a chat unread needs its last message"))?;
+                attributes.insert("type".cow(), NodeContent::Token("false"));
+                attributes.insert("count".cow(), NodeContent::String("-2".cow()));
+                last.into_attributes(&mut attributes);
+                "read"
+            }
+            ChatAction::Add => {
+                attributes.insert("type".cow(), NodeContent::Token("spam"));
+                "chat"
+            }
+            ChatAction::Remove => {
+                attributes.insert("type".cow(), NodeContent::Token("delete"));
+                "chat"
+            }
+            ChatAction::Clear { starred, last } => {
+                attributes.insert("type".cow(), NodeContent::Token("clear"));
+                attributes.insert("star".cow(), NodeContent::Token(if starred { "true" } else { "false" }));
+                if let Some(last) = last {
+                    last.into_attributes(&mut attributes);
+                }
+                "chat"
+            }
+            ChatAction::Pin(time) => {
+                attributes.insert("type".cow(), NodeContent::String("pin".cow()));
+                attributes.insert("pin".cow(), NodeContent::String(time.to_string().cow()));
+                "chat"
+            }
+            ChatAction::Unpin(previous) => {
+                let previous = previous.ok_or(WaError::UnsupportedAction("unpinning a chat needs the time it was pinned"))?;
+                attributes.insert("type".cow(), NodeContent::String("pin".cow()));
+                attributes.insert("previous".cow(), NodeContent::String(previous.to_string().cow()));
+                "chat"
+            }
+            ChatAction::Mute(time) => {
+                attributes.insert("type".cow(), NodeContent::Token("mute"));
+                attributes.insert("mute".cow(), NodeContent::String(time.to_string().cow()));
+                "chat"
+            }
+            ChatAction::Unmute(previous) => {
+                let previous = previous.ok_or(WaError::UnsupportedAction("unmuting a chat needs the time it was muted until"))?;
+                attributes.insert("type".cow(), NodeContent::Token("mute"));
+                attributes.insert("previous".cow(), NodeContent::String(previous.to_string().cow()));
+                "chat"
+            }
+            ChatAction::Archive => {
+                attributes.insert("type".cow(), NodeContent::Token("archive"));
+                "chat"
+            }
+            ChatAction::Unarchive => {
+                attributes.insert("type".cow(), NodeContent::Token("unarchive"));
+                "chat"
+            }
+        };
+        Ok(Node::new(desc, attributes, NodeContent::None))
+    }
     fn from_node(node: &mut Node) -> Result<ChatAction> {
         Ok(match node.take_attribute("type")?.as_str() {
             "spam" => ChatAction::Add,
             "delete" => ChatAction::Remove,
             "archive" => ChatAction::Archive,
             "unarchive" => ChatAction::Unarchive,
-            "clear" => ChatAction::Clear,
+            "clear" => ChatAction::Clear {
+                starred: node.take_attribute("star").ok().map_or(false, |star| star.as_str() == "true"),
+                last: LastMessage::from_node(node)
+            },
             "pin" => {
                 if let Ok(time) = node.take_attribute("pin") {
                     ChatAction::Pin(time.as_str().parse().map_err(|_| "NAN")?)
                 } else {
-                    ChatAction::Unpin
+                    ChatAction::Unpin(node.take_attribute("previous").ok().and_then(|t| t.as_str().parse().ok()))
                 }
             }
             "mute" => {
                 if let Ok(time) = node.take_attribute("mute") {
                     ChatAction::Mute(time.as_str().parse().map_err(|_| "NAN")?)
                 } else {
-                    ChatAction::Unmute
+                    ChatAction::Unmute(node.take_attribute("previous").ok().and_then(|t| t.as_str().parse().ok()))
                 }
             }
             x => bail_untyped!{ "invalid chat action type {}", x }
@@ -458,6 +698,24 @@ impl ChatAction {
     }
 }
 
+impl LastMessage {
+    /// Read the last message's details from a chat action, if they're there.
+    fn from_node(node: &mut Node) -> Option<LastMessage> {
+        let id = MessageId(node.take_attribute("index").ok()?.into_string());
+        Some(LastMessage {
+            id,
+            from_me: node.take_attribute("owner").ok().map_or(false, |owner| owner.as_str() == "true"),
+            participant: node.take_attribute("participant").ok().and_then(|p| p.into_jid().ok())
+        })
+    }
+    fn into_attributes(self, attributes: &mut HashMap<Cow<'static, str>, NodeContent>) {
+        attributes.insert("index".cow(), NodeContent::String(self.id.0.cow()));
+        attributes.insert("owner".cow(), NodeContent::Token(if self.from_me { "true" } else { "false" }));
+        if let Some(participant) = self.participant {
+            attributes.insert("participant".cow(), NodeContent::Jid(participant));
+        }
+    }
+}
 
 impl GroupParticipantsChange {
     fn into_node(self) -> &'static str {
@@ -470,6 +728,15 @@ impl GroupParticipantsChange {
     }
 }
 
+impl GroupSetting {
+    fn into_node(self) -> &'static str {
+        match self {
+            GroupSetting::Announce => "announcement",
+            GroupSetting::Restrict => "locked"
+        }
+    }
+}
+
 impl PresenceStatus {
     fn into_node(self) -> &'static str {
         match self {
@@ -480,3 +747,183 @@ impl PresenceStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use futures::SinkExt;
+    use crate::testing::*;
+    use crate::errors::WaError;
+    use crate::req::WaRequest;
+    use crate::node_wire::IntoCow;
+    use crate::message::{ChatMessage, ChatMessageContent, MessageId, MessageAckLevel};
+    use crate::event::WaEvent;
+    use crate::{ChatAction, LastMessage};
+
+    #[tokio::test]
+    async fn test_chat_actions() {
+        let chat = jid("447700900001@c.us");
+        let (mut conn, sent_rx) = connect_scripted(|mut client| async move {
+            let mut nodes = vec![];
+            for _ in 0..4 {
+                let (_, node) = client.recv_node().await.unwrap();
+                nodes.push(node);
+            }
+            (client, nodes)
+        }).await;
+
+        let last = LastMessage { id: MessageId("3EB0ABCDEF".into()), from_me: true, participant: None };
+        let send = |action| WaRequest::ChatAction { jid: chat.clone(), action };
+        for action in vec![ChatAction::Read(None), ChatAction::Unmute(None)] {
+            match conn.send(send(action)).await {
+                Err(WaError::UnsupportedAction(_)) => {},
+                x => panic!("expected UnsupportedAction, got {:?}", x)
+            }
+        }
+        conn.send(send(ChatAction::Remove)).await.unwrap();
+        conn.send(send(ChatAction::Clear { starred: true, last: Some(last.clone()) })).await.unwrap();
+        conn.send(send(ChatAction::Unread(Some(last)))).await.unwrap();
+        conn.send(send(ChatAction::Unpin(Some(1500000000)))).await.unwrap();
+
+        let actions = sent_rx.await.unwrap().into_iter().map(|node| match node.content {
+            NodeContent::List(mut children) => children.remove(0),
+            _ => panic!("action node has no children")
+        }).collect::<Vec<_>>();
+        assert_eq!(actions[0].desc, "chat");
+        assert_eq!(actions[0].attributes["type"].as_str(), "delete");
+        assert_eq!(actions[1].attributes["type"].as_str(), "clear");
+        assert_eq!(actions[1].attributes["star"].as_str(), "true");
+        assert_eq!(actions[1].attributes["index"].as_str(), "3EB0ABCDEF");
+        assert_eq!(actions[1].attributes["owner"].as_str(), "true");
+        assert_eq!(actions[2].desc, "read");
+        assert_eq!(actions[2].attributes["type"].as_str(), "false");
+        assert_eq!(actions[2].attributes["count"].as_str(), "-2");
+        assert_eq!(actions[3].attributes["type"].as_str(), "pin");
+        assert_eq!(actions[3].attributes["previous"].as_str(), "1500000000");
+        assert!(actions[3].attributes.get("pin").is_none());
+    }
+    #[tokio::test]
+    async fn test_message_info() {
+        let group = jid("447700900000-1234567890@g.us");
+        let mid = MessageId::generate();
+        let server_mid = mid.clone();
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let (tag, query) = client.recv_node().await.unwrap();
+            assert_eq!(query.attributes["type"].as_str(), "message_info");
+            assert_eq!(query.attributes["index"].as_str(), server_mid.0);
+            let user = |j: &str, t: &str| {
+                let mut node = Node::new_empty("user");
+                node.set_attribute("jid", NodeContent::Jid(jid(j)));
+                node.set_attribute("t", NodeContent::String(t.to_string().cow()));
+                node
+            };
+            let mut attributes = HashMap::new();
+            attributes.insert("type".cow(), NodeContent::Token("message_info"));
+            let resp = Node::new("response", attributes, NodeContent::List(vec![
+                Node::new("read", HashMap::new(), NodeContent::List(vec![user("447700900001@c.us", "1500000100")])),
+                Node::new("delivery", HashMap::new(), NodeContent::List(vec![
+                    user("447700900001@c.us", "1500000000"),
+                    user("447700900002@c.us", "1500000050")
+                ]))
+            ]));
+            client.send_node_tagged(&tag, resp).await.unwrap();
+            (client, ())
+        }).await;
+
+        let handle = spawn_handle(conn);
+        let receipts = handle.get_message_info(group, mid).await.unwrap();
+        let receipts = receipts.into_iter()
+            .map(|r| (r.jid, r.level as u8, r.time.unwrap().and_utc().timestamp()))
+            .collect::<Vec<_>>();
+        assert_eq!(receipts, vec![
+            (jid("447700900001@c.us"), MessageAckLevel::Read as u8, 1500000100),
+            (jid("447700900002@c.us"), MessageAckLevel::Received as u8, 1500000050)
+        ]);
+    }
+    #[tokio::test]
+    async fn test_starred_messages() {
+        let alice = jid("447700900001@c.us");
+        let bob = jid("447700900002@c.us");
+        let (a1, a2, b1) = (MessageId::generate(), MessageId::generate(), MessageId::generate());
+        let starred = ChatMessage::new(alice.clone(), ChatMessageContent::Text("remember this".into()));
+        let (server_alice, server_starred, server_a1) = (alice.clone(), starred.clone(), a1.clone());
+        let (mut conn, sent_rx) = connect_scripted(|mut client| async move {
+            let (_, node) = client.recv_node().await.unwrap();
+            let mut item = Node::new_empty("item");
+            item.set_attribute("index", NodeContent::String(server_a1.0.cow()));
+            item.set_attribute("owner", NodeContent::String("true".cow()));
+            let mut chat = Node::new("chat", HashMap::new(), NodeContent::List(vec![item]));
+            chat.set_attribute("jid", NodeContent::Jid(server_alice));
+            chat.set_attribute("type", NodeContent::String("unstar".cow()));
+            client.send_node(action("relay", vec![chat])).await.unwrap();
+            let (tag, query) = client.recv_node().await.unwrap();
+            assert_eq!(query.attributes["type"].as_str(), "star");
+            assert_eq!(query.attributes["count"].as_str(), "10");
+            assert!(query.attributes.get("jid").is_none());
+            let mut resp = history(vec![server_starred]);
+            resp.set_attribute("type", NodeContent::Token("star"));
+            client.send_node_tagged(&tag, resp).await.unwrap();
+            (client, node)
+        }).await;
+
+        conn.send(WaRequest::StarMessages(vec![
+            (alice.clone(), a1.clone(), true),
+            (bob.clone(), b1.clone(), false),
+            (alice.clone(), a2.clone(), false)
+        ])).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::StarChange { jid, starred, messages } => {
+                assert_eq!(jid, alice);
+                assert!(!starred);
+                assert_eq!(messages, vec![(a1.clone(), true)]);
+            },
+            _ => panic!("expected StarChange")
+        }
+
+        let handle = spawn_handle(conn);
+        let msgs = handle.get_starred_messages(None, 10, None).await.unwrap();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].id, starred.id);
+
+        let node = sent_rx.await.unwrap();
+        let chats = match node.content {
+            NodeContent::List(children) => children,
+            _ => panic!("action node has no children")
+        };
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[0].attributes["type"].as_str(), "star");
+        match chats[0].content {
+            NodeContent::List(ref items) => {
+                let ids = items.iter().map(|i| i.attributes["index"].as_str().to_string()).collect::<Vec<_>>();
+                assert_eq!(ids, vec![a1.0.clone(), a2.0.clone()]);
+                assert_eq!(items[0].attributes["owner"].as_str(), "true");
+            },
+            _ => panic!("chat node has no items")
+        }
+    }
+    #[tokio::test]
+    async fn test_search_messages() {
+        let chat = jid("447700900001@c.us");
+        let found = ChatMessage::new(chat.clone(), ChatMessageContent::Text("the wifi password is hunter2".into()));
+        let (server_chat, server_found) = (chat.clone(), found.clone());
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let (tag, query) = client.recv_node().await.unwrap();
+            assert_eq!(query.attributes["type"].as_str(), "search");
+            assert_eq!(query.attributes["search"].as_str(), "wifi password");
+            assert_eq!(query.attributes["page"].as_str(), "1");
+            assert_eq!(query.attributes["count"].as_str(), "20");
+            assert_eq!(query.attributes["jid"].clone().into_jid().unwrap(), server_chat);
+            let mut resp = history(vec![server_found]);
+            resp.set_attribute("type", NodeContent::Token("search"));
+            resp.set_attribute("last", NodeContent::Token("false"));
+            client.send_node_tagged(&tag, resp).await.unwrap();
+            (client, ())
+        }).await;
+
+        let handle = spawn_handle(conn);
+        let results = handle.search_messages("wifi password".into(), Some(chat), 1, 20).await.unwrap();
+        assert!(!results.last_page);
+        assert_eq!(results.messages.len(), 1);
+        assert_eq!(results.messages[0].id, found.id);
+    }
+}
@@ -0,0 +1,92 @@
+//! Rendering the QR codes from `WaEvent::ScanCode`.
+//!
+//! If you'd rather have the phone scan the code somewhere else entirely,
+//! the raw pairing string is in the event's `pairing` field; any QR code
+//! encoding that string will do.
+
+use qrcode::{Color, QrCode};
+use qrcode::render::svg;
+
+#[cfg(feature = "media")]
+use crate::errors::*;
+
+/// Width of the light border around the code, in modules.
+const QUIET_ZONE: usize = 2;
+
+/// Render a QR code as text, using Unicode half-block characters (so each
+/// line of text covers two rows of the code).
+///
+/// Set `dark_background` if the text will be displayed light-on-dark (as
+/// in most terminals); the colours are then swapped, so the code still
+/// comes out dark-on-light.
+pub fn render_unicode(code: &QrCode, dark_background: bool) -> String {
+    let width = code.width();
+    let colors = code.to_colors();
+    let full = width + 2 * QUIET_ZONE;
+    // Whether the module at (x, y), counting the quiet zone, should be
+    // drawn with a block character.
+    let filled = |x: usize, y: usize| {
+        let dark = if x < QUIET_ZONE || y < QUIET_ZONE || x >= width + QUIET_ZONE || y >= width + QUIET_ZONE {
+            false
+        }
+        else {
+            colors[(y - QUIET_ZONE) * width + (x - QUIET_ZONE)] == Color::Dark
+        };
+        dark != dark_background
+    };
+    let mut ret = String::with_capacity((full + 1) * (full + 1) / 2 * 3);
+    for y in (0..full).step_by(2) {
+        for x in 0..full {
+            let top = filled(x, y);
+            // Leave the bottom half blank if we've run out of rows.
+            let bottom = y + 1 < full && filled(x, y + 1);
+            ret.push(match (top, bottom) {
+                (true, true) => '\u{2588}',
+                (true, false) => '\u{2580}',
+                (false, true) => '\u{2584}',
+                (false, false) => ' '
+            });
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Render a QR code as an SVG document.
+pub fn render_svg(code: &QrCode) -> String {
+    code.render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build()
+}
+
+/// Render a QR code as a PNG image.
+#[cfg(feature = "media")]
+pub fn render_png(code: &QrCode) -> Result<Vec<u8>> {
+    let img = code.render::<image::Luma<u8>>()
+        .min_dimensions(256, 256)
+        .build();
+    let mut ret = Vec::new();
+    image::DynamicImage::ImageLuma8(img).write_to(&mut ret, image::ImageOutputFormat::PNG)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_unicode() {
+        let code = QrCode::new("1@ref,cHVia2V5,Y2xpZW50aWQ=").unwrap();
+        let full = code.width() + 2 * QUIET_ZONE;
+
+        let light = render_unicode(&code, false);
+        let lines: Vec<_> = light.lines().collect();
+        assert_eq!(lines.len(), (full + 1) / 2);
+        assert!(lines.iter().all(|l| l.chars().count() == full));
+        // The quiet zone is light, so on a light background it's blank.
+        assert!(lines[0].chars().all(|c| c == ' '));
+
+        let dark = render_unicode(&code, true);
+        assert!(dark.lines().next().unwrap().chars().all(|c| c == '\u{2588}'));
+    }
+}
@@ -0,0 +1,507 @@
+//! Automatically reconnecting wrapper around `WebConnection`.
+//!
+//! A `WebConnection` dies as soon as anything goes wrong with the underlying
+//! websocket. `ReconnectingConnection` remembers the most recent persistent
+//! session, and uses it to dial back in when that happens.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::time::Duration;
+use core::task::{Context, Poll};
+use futures::{Sink, Future, Stream};
+use futures::channel::mpsc;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::time::Delay;
+
+use crate::conn::WebConnection;
+use crate::config::ConnectionConfig;
+use crate::session::PersistentSession;
+use crate::event::WaEvent;
+use crate::req::WaRequest;
+use crate::handle::{ConnectionHandle, HandleRequest};
+use crate::errors::*;
+
+type ConnectFuture = Pin<Box<dyn Future<Output=WaResult<WebConnection>> + Send>>;
+
+/// Settings controlling how (and how often) reconnection is attempted.
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Delay before the first reconnection attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between attempts.
+    pub max_backoff: Duration,
+    /// Give up after this many consecutive failed attempts (`None` means
+    /// keep trying forever).
+    pub max_attempts: Option<u32>
+}
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(120),
+            max_attempts: None
+        }
+    }
+}
+impl ReconnectConfig {
+    /// Calculate the delay before the given attempt, using exponential
+    /// backoff with 'equal jitter' (i.e. somewhere between half and all
+    /// of the exponential delay).
+    fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let shift = attempt.saturating_sub(1).min(16);
+        let delay = self.initial_backoff.checked_mul(1u32 << shift)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let mut rand = [0u8; 4];
+        SystemRandom::new().fill(&mut rand).unwrap();
+        let frac = u32::from_le_bytes(rand) as f64 / u32::max_value() as f64;
+        let half = delay / 2;
+        half + half.mul_f64(frac)
+    }
+}
+
+enum SupervisorState {
+    /// We have a live connection.
+    Connected(WebConnection),
+    /// We're waiting to make another connection attempt.
+    Waiting(Delay),
+    /// A connection attempt is in progress.
+    Connecting(ConnectFuture),
+    /// We've given up, or the connection ended cleanly.
+    Closed
+}
+
+/// A `WebConnection` that reconnects itself when the connection is lost.
+///
+/// This remembers the most recent `PersistentSession` (from the
+/// `SessionEstablished` event), and uses it to call
+/// `WebConnection::connect_persistent()` again with exponential backoff
+/// whenever the connection fails. Reconnection attempts are signalled with
+/// `WaEvent::Reconnecting`, and success with `WaEvent::Reconnected`.
+///
+/// Requests sent while the connection is down are buffered, and sent
+/// once the new session has been established. This includes requests
+/// made through a `ConnectionHandle` obtained from `handle()`, which
+/// (unlike one obtained from the underlying `WebConnection`) stays valid
+/// across reconnections.
+///
+/// If the server tells us the session was replaced or removed
+/// (`WaError::Disconnected`), no reconnection is attempted and the error
+/// is returned as-is; that almost certainly needs a human to look at it.
+/// The same goes for the server rejecting the session when logging in
+/// (`WaError::StatusCode` with a 401 or 403 status).
+/// Errors that happen before any session has been established are also
+/// returned directly, since there's nothing to reconnect with.
+///
+/// Once it gives up, any requests still buffered are dropped, and
+/// requests made through a handle fail with `WaError::WebsocketDisconnected`.
+pub struct ReconnectingConnection {
+    state: SupervisorState,
+    config: ReconnectConfig,
+    /// Settings used for each new connection.
+    conn_config: ConnectionConfig,
+    session: Option<PersistentSession>,
+    /// Whether the current connection has an established session.
+    established: bool,
+    /// Whether we've reconnected, and should emit `Reconnected` on the
+    /// next `SessionEstablished`.
+    reconnected: bool,
+    attempt: u32,
+    pending: VecDeque<WaRequest>,
+    outbox: VecDeque<WaEvent>,
+    handle_tx: mpsc::UnboundedSender<HandleRequest>,
+    handle_rx: mpsc::UnboundedReceiver<HandleRequest>
+}
+impl std::marker::Unpin for ReconnectingConnection {}
+
+impl ReconnectingConnection {
+    /// Supervise an existing connection (made via `connect_new()` or
+    /// `connect_persistent()`).
+    ///
+    /// Reconnection attempts reuse the connection's `ConnectionConfig`.
+    pub fn new(conn: WebConnection, config: ReconnectConfig) -> Self {
+        let conn_config = conn.config().clone();
+        Self::with_state(SupervisorState::Connected(conn), config, conn_config, None, 0)
+    }
+    /// Connect to WhatsApp Web using a persistent session, reconnecting
+    /// as necessary.
+    ///
+    /// Unlike `WebConnection::connect_persistent()`, this returns
+    /// immediately; failure to connect the first time is treated like any
+    /// other connection loss.
+    pub fn connect_persistent(sess: PersistentSession, config: ReconnectConfig) -> Self {
+        Self::connect_persistent_with(sess, ConnectionConfig::default(), config)
+    }
+    /// Like `connect_persistent()`, but using the given `ConnectionConfig`
+    /// for every connection attempt.
+    pub fn connect_persistent_with(sess: PersistentSession, conn_config: ConnectionConfig, config: ReconnectConfig) -> Self {
+        let fut = Box::pin(WebConnection::connect_persistent_with(sess.clone(), conn_config.clone()));
+        Self::with_state(SupervisorState::Connecting(fut), config, conn_config, Some(sess), 1)
+    }
+    fn with_state(state: SupervisorState, config: ReconnectConfig, conn_config: ConnectionConfig, session: Option<PersistentSession>, attempt: u32) -> Self {
+        let (handle_tx, handle_rx) = mpsc::unbounded();
+        Self {
+            state,
+            config,
+            conn_config,
+            session,
+            established: false,
+            reconnected: false,
+            attempt,
+            pending: VecDeque::new(),
+            outbox: VecDeque::new(),
+            handle_tx,
+            handle_rx
+        }
+    }
+    /// Get a handle that can be used to make requests and await their results.
+    ///
+    /// Requests made while the connection is down are held until it's
+    /// been reestablished.
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle::new(self.handle_tx.clone())
+    }
+    /// The most recent persistent session we know about, if any.
+    pub fn session(&self) -> Option<&PersistentSession> {
+        self.session.as_ref()
+    }
+    /// Whether requests can currently be sent straight through
+    /// (as opposed to being buffered).
+    pub fn is_connected(&self) -> bool {
+        self.established && self.connection().is_some()
+    }
+    fn connection(&self) -> Option<&WebConnection> {
+        match self.state {
+            SupervisorState::Connected(ref c) => Some(c),
+            _ => None
+        }
+    }
+    fn is_fatal(&self, e: &WaError) -> bool {
+        match *e {
+            WaError::Disconnected(_) => true,
+            // The server rejected our session while logging in; trying it
+            // again isn't going to help.
+            WaError::StatusCode(401) | WaError::StatusCode(403) if !self.established => true,
+            _ => self.session.is_none()
+        }
+    }
+    /// Stop for good, failing anything still waiting on a connection.
+    fn close(&mut self) {
+        self.state = SupervisorState::Closed;
+        self.established = false;
+        if self.pending.len() > 0 {
+            warn!("Dropping {} requests buffered while offline", self.pending.len());
+            self.pending.clear();
+        }
+        // Closing the channel makes any later requests through a handle
+        // fail straight away.
+        self.handle_rx.close();
+        while let Ok(hr) = self.handle_rx.try_recv() {
+            let _ = hr.resp.send(Err(WaError::WebsocketDisconnected));
+        }
+    }
+    /// Schedule another connection attempt, or give up if we've tried too
+    /// many times already.
+    fn schedule_reconnect(&mut self, e: WaError) -> WaResult<()> {
+        // Only a connection that actually got going counts as having been
+        // reconnected, once we get another one.
+        if self.established {
+            self.reconnected = true;
+        }
+        self.established = false;
+        self.attempt += 1;
+        if let Some(max) = self.config.max_attempts {
+            if self.attempt > max {
+                error!("Giving up reconnecting after {} attempts", max);
+                self.close();
+                return Err(e);
+            }
+        }
+        let delay = self.config.delay_for_attempt(self.attempt);
+        warn!("Connection lost ({}); reconnecting in {:?} (attempt {})", e, delay, self.attempt);
+        self.state = SupervisorState::Waiting(tokio::time::delay_for(delay));
+        self.outbox.push_back(WaEvent::Reconnecting {
+            attempt: self.attempt,
+            delay
+        });
+        Ok(())
+    }
+    /// Send any requests buffered while we were offline, for as long as
+    /// the connection is ready to take them.
+    fn drain_pending(&mut self, cx: &mut Context<'_>) -> WaResult<()> {
+        if !self.established || self.pending.len() == 0 {
+            return Ok(());
+        }
+        if let SupervisorState::Connected(ref mut conn) = self.state {
+            debug!("Sending {} requests buffered while offline", self.pending.len());
+            while self.pending.len() > 0 {
+                if let Poll::Pending = Pin::new(&mut *conn).poll_ready(cx)? {
+                    break;
+                }
+                let req = self.pending.pop_front().unwrap();
+                Pin::new(&mut *conn).start_send(req)?;
+            }
+            // Failures here will show up on the next poll of the stream.
+            let _ = Pin::new(conn).poll_flush(cx);
+        }
+        Ok(())
+    }
+    fn on_event(&mut self, cx: &mut Context<'_>, evt: WaEvent) -> WaResult<()> {
+        let mut reconnected = false;
+        if let WaEvent::SessionEstablished { ref persistent, .. } = evt {
+            self.session = Some(persistent.clone());
+            self.established = true;
+            self.attempt = 0;
+            reconnected = self.reconnected;
+            self.reconnected = false;
+            self.drain_pending(cx)?;
+        }
+        if let WaEvent::LoggedOut = evt {
+            // The session's dead now; don't try to reuse it.
+            self.session = None;
+        }
+        self.outbox.push_back(evt);
+        if reconnected {
+            self.outbox.push_back(WaEvent::Reconnected);
+        }
+        Ok(())
+    }
+}
+
+impl Stream for ReconnectingConnection {
+    type Item = WaResult<WaEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<WaResult<WaEvent>>> {
+        loop {
+            if let Some(evt) = self.outbox.pop_front() {
+                return Poll::Ready(Some(Ok(evt)));
+            }
+            let handle = match self.state {
+                SupervisorState::Connected(ref conn) if self.established => Some(conn.handle()),
+                _ => None
+            };
+            if let Some(handle) = handle {
+                while let Poll::Ready(Some(hr)) = Pin::new(&mut self.handle_rx).poll_next(cx) {
+                    handle.forward(hr);
+                }
+            }
+            self.drain_pending(cx)?;
+            let next = match self.state {
+                SupervisorState::Connected(ref mut conn) => {
+                    match Pin::new(conn).poll_next(cx) {
+                        Poll::Ready(Some(Ok(evt))) => {
+                            self.on_event(cx, evt)?;
+                            continue;
+                        },
+                        Poll::Ready(Some(Err(e))) => {
+                            if self.is_fatal(&e) {
+                                self.close();
+                                return Poll::Ready(Some(Err(e)));
+                            }
+                            self.schedule_reconnect(e)?;
+                            continue;
+                        },
+                        Poll::Ready(None) => {
+                            self.close();
+                            return Poll::Ready(None);
+                        },
+                        Poll::Pending => return Poll::Pending
+                    }
+                },
+                SupervisorState::Waiting(ref mut delay) => {
+                    match Pin::new(delay).poll(cx) {
+                        Poll::Ready(_) => {
+                            let sess = match self.session.clone() {
+                                Some(s) => s,
+                                None => {
+                                    error!("Waiting to reconnect without a session");
+                                    self.close();
+                                    return Poll::Ready(Some(Err(WaError::InvalidSessionState)));
+                                }
+                            };
+                            debug!("Attempting to reconnect (attempt {})", self.attempt);
+                            let fut = WebConnection::connect_persistent_with(sess, self.conn_config.clone());
+                            SupervisorState::Connecting(Box::pin(fut))
+                        },
+                        Poll::Pending => return Poll::Pending
+                    }
+                },
+                SupervisorState::Connecting(ref mut fut) => {
+                    match fut.as_mut().poll(cx) {
+                        Poll::Ready(Ok(conn)) => SupervisorState::Connected(conn),
+                        Poll::Ready(Err(e)) => {
+                            if self.is_fatal(&e) {
+                                self.close();
+                                return Poll::Ready(Some(Err(e)));
+                            }
+                            self.schedule_reconnect(e)?;
+                            continue;
+                        },
+                        Poll::Pending => return Poll::Pending
+                    }
+                },
+                SupervisorState::Closed => return Poll::Ready(None)
+            };
+            self.state = next;
+        }
+    }
+}
+
+impl Sink<WaRequest> for ReconnectingConnection {
+    type Error = WaError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<WaResult<()>> {
+        let direct = self.established && self.pending.len() == 0;
+        match self.state {
+            SupervisorState::Connected(ref mut conn) if direct => Pin::new(conn).poll_ready(cx),
+            _ => Poll::Ready(Ok(()))
+        }
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: WaRequest) -> WaResult<()> {
+        // Requests still buffered from while we were offline go first.
+        let direct = self.established && self.pending.len() == 0;
+        match self.state {
+            SupervisorState::Connected(ref mut conn) if direct => Pin::new(conn).start_send(item),
+            SupervisorState::Closed => Err(WaError::WebsocketDisconnected),
+            _ => {
+                self.pending.push_back(item);
+                Ok(())
+            }
+        }
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<WaResult<()>> {
+        match self.state {
+            SupervisorState::Connected(ref mut conn) => Pin::new(conn).poll_flush(cx),
+            _ => Poll::Ready(Ok(()))
+        }
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<WaResult<()>> {
+        match self.state {
+            SupervisorState::Connected(ref mut conn) => Pin::new(conn).poll_close(cx),
+            _ => Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use crate::testing::{MockServer, test_session, jid, TEST_JID};
+
+    #[test]
+    fn test_backoff_bounds() {
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None
+        };
+        for attempt in 1..40 {
+            let full = Duration::from_secs(2 * (1u64 << (attempt - 1).min(16))).min(config.max_backoff);
+            let delay = config.delay_for_attempt(attempt);
+            assert!(delay >= full / 2, "attempt {}: {:?} < {:?}", attempt, delay, full / 2);
+            assert!(delay <= full, "attempt {}: {:?} > {:?}", attempt, delay, full);
+        }
+    }
+    #[tokio::test]
+    async fn test_rejected_takeover_is_fatal() {
+        let mut server = MockServer::bind().await.unwrap();
+        let conn_config = server.config();
+        tokio::spawn(async move {
+            let mut client = server.accept().await.unwrap();
+            client.accept_init().await.unwrap();
+            let (tag, j) = client.recv_json().await.unwrap();
+            assert_eq!(j[1], "login");
+            client.reply_status(&tag, 401).await.unwrap();
+            client.hold_open().await
+        });
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            max_attempts: None
+        };
+        let mut conn = ReconnectingConnection::connect_persistent_with(test_session(), conn_config, config);
+        loop {
+            match conn.next().await {
+                Some(Ok(WaEvent::Reconnecting { .. })) => panic!("tried to reconnect with a rejected session"),
+                Some(Ok(_)) => {},
+                Some(Err(WaError::StatusCode(401))) => break,
+                x => panic!("expected the rejection, got {:?}", x.map(|r| r.err()))
+            }
+        }
+        assert!(conn.next().await.is_none());
+    }
+    #[tokio::test]
+    async fn test_first_connect_failure_is_not_a_reconnection() {
+        let sess = test_session();
+        let mut server = MockServer::bind().await.unwrap();
+        let conn_config = server.config();
+        let server_sess = sess.clone();
+        tokio::spawn(async move {
+            // Hang up on the first attempt, then let the second one in.
+            drop(server.accept().await.unwrap());
+            let mut client = server.accept().await.unwrap();
+            client.login_persistent(&server_sess, &jid(TEST_JID), false).await.unwrap();
+            client.hold_open().await
+        });
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            max_attempts: None
+        };
+        let mut conn = ReconnectingConnection::connect_persistent_with(sess, conn_config, config);
+        let mut reconnecting = false;
+        loop {
+            match conn.next().await.unwrap().unwrap() {
+                WaEvent::Reconnecting { .. } => reconnecting = true,
+                WaEvent::SessionEstablished { .. } => break,
+                _ => {}
+            }
+        }
+        assert!(reconnecting);
+        // Nothing follows the login, so this can only time out.
+        while let Ok(evt) = tokio::time::timeout(Duration::from_millis(100), conn.next()).await {
+            if let Some(Ok(WaEvent::Reconnected)) = evt {
+                panic!("never had a connection to lose");
+            }
+        }
+    }
+    #[tokio::test]
+    async fn test_giving_up_fails_waiting_requests() {
+        let mut server = MockServer::bind().await.unwrap();
+        let conn_config = server.config();
+        tokio::spawn(async move {
+            loop {
+                drop(server.accept().await.unwrap());
+            }
+        });
+        let config = ReconnectConfig {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            max_attempts: Some(2)
+        };
+        let mut conn = ReconnectingConnection::connect_persistent_with(test_session(), conn_config, config);
+        let handle = conn.handle();
+        conn.send(WaRequest::GetProfileStatus(jid(TEST_JID))).await.unwrap();
+        let drive = async {
+            let mut failed = false;
+            while let Some(evt) = conn.next().await {
+                failed |= evt.is_err();
+            }
+            assert!(failed);
+        };
+        let request = handle.request(WaRequest::GetProfileStatus(jid(TEST_JID)));
+        let (res, _) = tokio::time::timeout(Duration::from_secs(5), futures::future::join(request, drive)).await
+            .expect("request never failed");
+        match res {
+            Err(WaError::WebsocketDisconnected) => {},
+            _ => panic!("expected WebsocketDisconnected")
+        }
+        assert!(conn.pending.is_empty());
+        assert!(conn.send(WaRequest::GetProfileStatus(jid(TEST_JID))).await.is_err());
+        match handle.request(WaRequest::GetProfileStatus(jid(TEST_JID))).await {
+            Err(WaError::WebsocketDisconnected) => {},
+            _ => panic!("expected WebsocketDisconnected")
+        }
+    }
+}
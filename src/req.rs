@@ -2,7 +2,8 @@
 
 use crate::message::{MessageId, ChatMessage, Peer};
 use crate::conn::{WebConnection, CallbackType};
-use crate::{Jid, PresenceStatus, GroupParticipantsChange, ChatAction, MediaType};
+use crate::event::WaEvent;
+use crate::{Jid, PresenceStatus, GroupParticipantsChange, GroupSetting, ChatAction, MediaType, ProfilePicture};
 use crate::websocket_protocol::WebsocketMessageMetric;
 use crate::node_protocol::{AppEvent, AppMessage, MessageEventType, GroupCommand, Query};
 use crate::json_protocol;
@@ -37,6 +38,16 @@ pub enum WaRequest {
         action: ChatAction
     },
     SendMessage(ChatMessage),
+    /// Revoke (i.e. 'delete for everyone') a message we sent earlier.
+    ///
+    /// This is shorthand for sending `ChatMessage::revoke(jid, mid)`; as with
+    /// any other message, a `MessageAck` (or `MessageSendFail`) follows.
+    RevokeMessage {
+        /// The JID of the chat the message was sent to.
+        jid: Jid,
+        /// The ID of the message to revoke.
+        mid: MessageId
+    },
     CreateGroup {
         subject: String,
         participants: Vec<Jid>
@@ -46,6 +57,51 @@ pub enum WaRequest {
         change: GroupParticipantsChange,
         participants: Vec<Jid>
     },
+    /// Leave a group chat.
+    ///
+    /// Like all the group requests, this results in a `WaEvent::GroupAck`
+    /// (or `GroupCommandFail`) once the server has responded.
+    LeaveGroup(Jid),
+    /// Change the subject of a group chat.
+    SetGroupSubject {
+        jid: Jid,
+        subject: String
+    },
+    /// Set the description of a group chat, or clear it if `description`
+    /// is `None`.
+    SetGroupDescription {
+        jid: Jid,
+        description: Option<String>,
+        /// The ID of the current description, if there is one
+        /// (see `GroupMetadata::description_id`).
+        prev_id: Option<String>
+    },
+    /// Turn on or off one of the settings restricting what non-admins can do.
+    SetGroupSetting {
+        jid: Jid,
+        setting: GroupSetting,
+        enabled: bool
+    },
+    /// Delete a group chat that we own.
+    DeleteGroup(Jid),
+    /// Get the invite code for a group chat (the bit after
+    /// `https://chat.whatsapp.com/` in an invite link).
+    ///
+    /// This results in a `WaEvent::GroupInviteCode` event.
+    GetGroupInviteCode(Jid),
+    /// Revoke a group chat's invite code, so old links stop working,
+    /// and generate a new one.
+    ///
+    /// This results in a `WaEvent::GroupInviteCode` event with the new code.
+    ResetGroupInviteCode(Jid),
+    /// Find out which group an invite code is for, without joining it.
+    ///
+    /// This results in a `WaEvent::GroupInviteInfo` event.
+    GetGroupInviteInfo(String),
+    /// Join a group chat using an invite code.
+    ///
+    /// This results in a `WaEvent::GroupJoined` event.
+    JoinGroup(String),
     /// Get message history for a given chat.
     ///
     /// This request returns history before the given message ID,
@@ -59,11 +115,85 @@ pub enum WaRequest {
         jid: Jid,
         /// The message ID to receive history before.
         mid: MessageId,
+        /// Whether we sent that message.
+        from_me: bool,
         /// The maximum amount of messages to receive.
         count: u16,
         /// An identifier for this history request.
         uuid: Uuid,
     },
+    /// Get message history after the given message ID, up to a total of
+    /// `count` messages.
+    ///
+    /// Like `GetMessageHistoryBefore`, this results in a
+    /// `WebEvent::MessageHistory` event.
+    GetMessageHistoryAfter {
+        jid: Jid,
+        mid: MessageId,
+        from_me: bool,
+        count: u16,
+        uuid: Uuid,
+    },
+    /// Get up to `count` messages of history surrounding the given message ID.
+    ///
+    /// Like `GetMessageHistoryBefore`, this results in a
+    /// `WebEvent::MessageHistory` event.
+    GetMessageHistoryAround {
+        jid: Jid,
+        mid: MessageId,
+        from_me: bool,
+        count: u16,
+        uuid: Uuid,
+    },
+    /// Get the latest `count` messages in a chat.
+    ///
+    /// Like `GetMessageHistoryBefore`, this results in a
+    /// `WebEvent::MessageHistory` event.
+    GetLatestMessages {
+        jid: Jid,
+        count: u16,
+        uuid: Uuid,
+    },
+    /// Star messages, each given by the JID of its chat, its ID, and
+    /// whether we sent it.
+    StarMessages(Vec<(Jid, MessageId, bool)>),
+    /// Unstar messages, given in the same way as for `StarMessages`.
+    UnstarMessages(Vec<(Jid, MessageId, bool)>),
+    /// Get starred messages, either in one chat or across all chats.
+    ///
+    /// This returns up to `count` messages, starting from the most recently
+    /// starred one, or from the one before `before` (the ID of a starred
+    /// message, and whether we sent it) to get the next page.
+    ///
+    /// Like the history requests, this results in a
+    /// `WebEvent::MessageHistory` event.
+    GetStarredMessages {
+        jid: Option<Jid>,
+        count: u16,
+        before: Option<(MessageId, bool)>,
+        uuid: Uuid,
+    },
+    /// Search for messages containing some text, either in one chat or
+    /// across all chats.
+    ///
+    /// Results come in pages of up to `count` messages, starting with
+    /// page 1. This results in a `WaEvent::SearchResults` event, with the
+    /// `uuid` supplied here.
+    SearchMessages {
+        query: String,
+        jid: Option<Jid>,
+        page: u32,
+        count: u16,
+        uuid: Uuid,
+    },
+    /// Find out who has received, read or played a message we sent.
+    ///
+    /// This results in a `WaEvent::MessageInfo` event.
+    GetMessageInfo {
+        /// The JID of the chat the message was sent to.
+        jid: Jid,
+        mid: MessageId,
+    },
     RequestFileUpload {
         hash: Vec<u8>,
         media_type: MediaType,
@@ -73,36 +203,64 @@ pub enum WaRequest {
         uuid: Uuid,
     },
     GetProfilePicture(Jid),
+    /// Set the profile picture of our own account, or a group chat we
+    /// administer, or remove it if `image` is `None`.
+    ///
+    /// A `WaEvent::PictureChange` (or `PictureChangeFail`) event reports
+    /// how it went.
+    SetProfilePicture {
+        jid: Jid,
+        image: Option<ProfilePicture>
+    },
     GetProfileStatus(Jid),
     GetGroupMetadata(Jid),
+    /// Ask the server which of the given phone numbers are registered
+    /// on WhatsApp (see `Jid::from_phonenumber` for the accepted formats).
+    ///
+    /// Each number results in a `WaEvent::NumberChecked` event, giving
+    /// the canonical JID for the number if it exists. Malformed numbers
+    /// aren't sent; their events (which come first) carry the error.
+    CheckNumbers(Vec<String>),
+    /// Log out, telling the server to forget this session.
+    ///
+    /// The connection is then closed, and ends with a `WaEvent::LoggedOut`
+    /// event. The `PersistentSession` can't be used again afterwards; if
+    /// a `SessionStore` is configured, it gets deleted from there.
+    Logout,
 }
 impl WaRequest {
-    pub(crate) fn apply(self, mut conn: Pin<&mut WebConnection>) -> Result<()> {
+    /// Send the request over the connection, returning the tags of the
+    /// messages sent whose responses the request is waiting on.
+    pub(crate) fn apply(self, mut conn: Pin<&mut WebConnection>) -> Result<Vec<String>> {
         use self::WaRequest::*;
 
-        match self {
+        let tag = match self {
             MessagePlayed { mid, peer } => {
                 conn.increment_epoch();
-                conn.send_set_app_event(WebsocketMessageMetric::Received, AppEvent::MessagePlayed { id: mid, peer })?;
+                conn.send_set_app_event(WebsocketMessageMetric::Received, AppEvent::MessagePlayed { id: mid, peer })?
             },
             MessageRead { mid, peer } => {
-                conn.send_set_app_event(WebsocketMessageMetric::Read, AppEvent::MessageRead { id: mid, peer })?;
+                conn.send_set_app_event(WebsocketMessageMetric::Read, AppEvent::MessageRead { id: mid, peer })?
             },
             SetPresence { presence, jid } => {
-                conn.send_set_app_event(WebsocketMessageMetric::Presence, AppEvent::PresenceChange(presence, jid))?;
+                conn.send_set_app_event(WebsocketMessageMetric::Presence, AppEvent::PresenceChange(presence, jid))?
             },
             SetStatus(st) => {
-                conn.send_set_app_event(WebsocketMessageMetric::Status, AppEvent::StatusChange(st))?;
+                conn.send_set_app_event(WebsocketMessageMetric::Status, AppEvent::StatusChange(st))?
             },
             SetNotifyName(st) => {
-                conn.send_set_app_event(WebsocketMessageMetric::Profile, AppEvent::NotifyChange(st))?;
+                conn.send_set_app_event(WebsocketMessageMetric::Profile, AppEvent::NotifyChange(st))?
             },
             SetProfileBlocked { jid, blocked } => {
                 let unblock = !blocked;
-                conn.send_set_app_event(WebsocketMessageMetric::Block, AppEvent::BlockProfile { unblock, jid })?;
+                conn.send_set_app_event(WebsocketMessageMetric::Block, AppEvent::BlockProfile { unblock, jid })?
             },
             ChatAction { jid, action } => {
-                conn.send_set_app_event(WebsocketMessageMetric::Chat, AppEvent::ChatAction(jid, action))?;
+                let metric = match action {
+                    crate::ChatAction::Read(_) | crate::ChatAction::Unread(_) => WebsocketMessageMetric::Read,
+                    _ => WebsocketMessageMetric::Chat
+                };
+                conn.send_set_app_event(metric, AppEvent::ChatAction(jid, action))?
             },
             SendMessage(msg) => {
                 if !msg.direction.is_sending() {
@@ -110,43 +268,327 @@ impl WaRequest {
                 }
                 let mid = msg.id.clone();
                 let amsg = AppMessage::MessagesEvents(Some(MessageEventType::Relay), vec![AppEvent::Message(msg)]);
-                conn.send_app_message(Some(mid.0.clone()), WebsocketMessageMetric::Message, amsg, CallbackType::ProcessAck { mid })?;
+                conn.send_app_message(Some(mid.0.clone()), WebsocketMessageMetric::Message, amsg, CallbackType::ProcessAck { mid })?
+            },
+            RevokeMessage { jid, mid } => {
+                return SendMessage(ChatMessage::revoke(jid, mid)).apply(conn);
             },
             CreateGroup { subject, participants } => {
-                conn.send_group_command(GroupCommand::Create(subject), participants)?;
+                conn.send_group_command(GroupCommand::Create(subject), participants)?
             },
             ChangeGroupParticipants { jid, change, participants } => {
-                conn.send_group_command(GroupCommand::ParticipantsChange(jid, change), participants)?;
+                conn.send_group_command(GroupCommand::ParticipantsChange(jid, change), participants)?
+            },
+            LeaveGroup(jid) => {
+                conn.send_group_command(GroupCommand::Leave(jid), vec![])?
+            },
+            SetGroupSubject { jid, subject } => {
+                conn.send_group_command(GroupCommand::Subject(jid, subject), vec![])?
+            },
+            SetGroupDescription { jid, description, prev_id } => {
+                let id = MessageId::generate().0;
+                conn.send_group_command(GroupCommand::Description { jid, id, prev: prev_id, description }, vec![])?
+            },
+            SetGroupSetting { jid, setting, enabled } => {
+                conn.send_group_command(GroupCommand::Setting(jid, setting, enabled), vec![])?
+            },
+            DeleteGroup(jid) => {
+                conn.send_group_command(GroupCommand::Delete(jid), vec![])?
+            },
+            GetGroupInviteCode(jid) => {
+                let req = json_protocol::build_invite_code_request(&jid);
+                conn.send_json_message(req, CallbackType::InviteCode { jid })
+            },
+            ResetGroupInviteCode(jid) => {
+                let req = json_protocol::build_invite_code_reset_request(&jid);
+                conn.send_json_message(req, CallbackType::InviteCode { jid })
+            },
+            GetGroupInviteInfo(code) => {
+                let req = json_protocol::build_invite_info_request(&code);
+                conn.send_json_message(req, CallbackType::InviteInfo { code })
+            },
+            JoinGroup(code) => {
+                let req = json_protocol::build_invite_join_request(&code);
+                conn.send_json_message(req, CallbackType::InviteJoin { code })
             },
             RequestFileUpload { hash, media_type, uuid } => {
                 let req = json_protocol::build_file_upload_request(&hash, media_type);
-                conn.send_json_message(req, CallbackType::FileUpload { uuid });
+                conn.send_json_message(req, CallbackType::FileUpload { uuid })
             },
             RequestMediaConn { uuid } => {
                 let req = json_protocol::build_media_conn_request();
-                conn.send_json_message(req, CallbackType::MediaConn { uuid });
+                conn.send_json_message(req, CallbackType::MediaConn { uuid })
             }
-            GetMessageHistoryBefore { jid, mid, count, uuid } => {
-                let msg = AppMessage::Query(Query::MessagesBefore { jid, id: mid.0, count });
-                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessagesBefore { uuid })?;
+            GetMessageHistoryBefore { jid, mid, from_me, count, uuid } => {
+                let msg = AppMessage::Query(Query::MessagesBefore { jid, id: mid.0, from_me, count });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessageHistory { uuid })?
+            },
+            GetMessageHistoryAfter { jid, mid, from_me, count, uuid } => {
+                let msg = AppMessage::Query(Query::MessagesAfter { jid, id: mid.0, from_me, count });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessageHistory { uuid })?
+            },
+            GetMessageHistoryAround { jid, mid, from_me, count, uuid } => {
+                let msg = AppMessage::Query(Query::MessagesAround { jid, id: mid.0, from_me, count });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessageHistory { uuid })?
+            },
+            GetLatestMessages { jid, count, uuid } => {
+                let msg = AppMessage::Query(Query::MessagesLast { jid, count });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessageHistory { uuid })?
+            },
+            StarMessages(messages) => {
+                conn.send_star_messages(messages, true)?
+            },
+            UnstarMessages(messages) => {
+                conn.send_star_messages(messages, false)?
+            },
+            GetStarredMessages { jid, count, before, uuid } => {
+                let msg = AppMessage::Query(Query::Starred { jid, count, before });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, CallbackType::MessageHistory { uuid })?
+            },
+            SearchMessages { query, jid, page, count, uuid } => {
+                let msg = AppMessage::Query(Query::Search { query, jid, page, count });
+                conn.send_app_message(None, WebsocketMessageMetric::QuerySearch, msg, CallbackType::SearchResults { uuid })?
+            },
+            GetMessageInfo { jid, mid } => {
+                let msg = AppMessage::Query(Query::MessageInfo { jid, id: mid.0.clone() });
+                conn.send_app_message(None, WebsocketMessageMetric::QueryMessageInfo, msg, CallbackType::MessageInfo { mid })?
             },
             GetProfilePicture(jid) => {
                 let req = json_protocol::build_profile_picture_request(&jid);
-                conn.send_json_message(req, CallbackType::ProfilePicture { jid });
+                conn.send_json_message(req, CallbackType::ProfilePicture { jid })
+            },
+            SetProfilePicture { jid, image } => {
+                conn.send_set_picture(jid, image)?
             },
             GetProfileStatus(jid) => {
                 let req = json_protocol::build_profile_status_request(&jid);
-                conn.send_json_message(req, CallbackType::ProfileStatus { jid });
+                conn.send_json_message(req, CallbackType::ProfileStatus { jid })
             },
             GetGroupMetadata(jid) => {
                 let req = json_protocol::build_group_metadata_request(&jid);
-                conn.send_json_message(req, CallbackType::GroupMetadata);
+                conn.send_json_message(req, CallbackType::GroupMetadata)
+            },
+            CheckNumbers(numbers) => {
+                let mut tags = vec![];
+                for number in numbers {
+                    match Jid::from_phonenumber(number.clone()) {
+                        Ok(jid) => {
+                            let req = json_protocol::build_exist_request(&jid);
+                            tags.push(conn.send_json_message(req, CallbackType::NumberExists { number }));
+                        },
+                        Err(e) => conn.push_event(WaEvent::NumberChecked { number, jid: Err(e) })
+                    }
+                }
+                return Ok(tags);
             },
             SubscribePresence(jid) => {
                 let req = json_protocol::build_presence_subscribe(&jid);
-                conn.send_json_message(req, CallbackType::Noop);
+                conn.send_json_message(req, CallbackType::Noop)
+            },
+            Logout => {
+                conn.logout();
+                return Ok(vec![]);
+            },
+        };
+        Ok(vec![tag])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use crate::testing::*;
+    use crate::event::WaEvent;
+    use crate::errors::WaError;
+    use super::WaRequest;
+
+    #[tokio::test]
+    async fn test_group_admin() {
+        use crate::GroupSetting;
+
+        let group = jid("447700900000-1234567890@g.us");
+        let server_group = group.clone();
+        let (mut conn, sent_rx) = connect_scripted(|mut client| async move {
+            let prop = client.ack_node(200).await.unwrap();
+            client.send_server_message(array!["Chat", object!{
+                "id" => server_group.to_string(),
+                "cmd" => "action",
+                "data" => array!["announce", "447700900001@c.us", object!{ "value" => "true" }]
+            }]).await.unwrap();
+            let leave = client.ack_node(403).await.unwrap();
+            (client, (prop, leave))
+        }).await;
+
+        conn.send(WaRequest::SetGroupSetting { jid: group.clone(), setting: GroupSetting::Announce, enabled: true }).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupAck { jid } => assert_eq!(jid.as_ref(), Some(&group)),
+            _ => panic!("expected GroupAck")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::GroupSettingChange { jid, setting, value, .. } => {
+                assert_eq!(jid, group);
+                assert_eq!(setting, GroupSetting::Announce);
+                assert!(value);
+            },
+            _ => panic!("expected GroupSettingChange")
+        }
+        conn.send(WaRequest::LeaveGroup(group.clone())).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupCommandFail { jid, status } => {
+                assert_eq!(jid.as_ref(), Some(&group));
+                assert_eq!(status, 403);
+            },
+            _ => panic!("expected GroupCommandFail")
+        }
+
+        let (prop, leave) = sent_rx.await.unwrap();
+        let command = |node: Node| match node.content {
+            NodeContent::List(mut children) => children.remove(0),
+            _ => panic!("action node has no children")
+        };
+        let leave = command(leave);
+        assert_eq!(leave.desc, "group");
+        assert_eq!(leave.attributes["type"].as_str(), "leave");
+        assert_eq!(leave.attributes["jid"].clone().into_jid().unwrap(), group);
+        if let NodeContent::List(ref children) = leave.content {
+            assert!(children.is_empty());
+        }
+        let cmd = command(prop);
+        assert_eq!(cmd.desc, "group");
+        assert_eq!(cmd.attributes["type"].as_str(), "prop");
+        match cmd.content {
+            NodeContent::List(ref children) => {
+                assert_eq!(children[0].desc, "announcement");
+                assert_eq!(children[0].attributes["value"].as_str(), "true");
+            },
+            _ => panic!("group node has no children")
+        }
+    }
+    #[tokio::test]
+    async fn test_group_command_before_login() {
+        use crate::WebConnection;
+
+        let mut server = MockServer::bind().await.unwrap();
+        let config = server.config();
+        tokio::spawn(async move {
+            // Never answer the login, so we don't learn our own JID.
+            server.accept().await.unwrap().hold_open().await
+        });
+        let mut conn = WebConnection::connect_persistent_with(test_session(), config).await.unwrap();
+        let group = jid("447700900000-1234567890@g.us");
+        match conn.send(WaRequest::LeaveGroup(group.clone())).await {
+            Err(WaError::NoJidYet) => {},
+            x => panic!("expected NoJidYet, got {:?}", x)
+        }
+        let handle = spawn_handle(conn);
+        match handle.request(WaRequest::SetGroupSubject { jid: group, subject: "renamed".into() }).await {
+            Err(WaError::NoJidYet) => {},
+            _ => panic!("expected NoJidYet")
+        }
+    }
+    #[tokio::test]
+    async fn test_group_invites() {
+        let group = jid("447700900000-1234567890@g.us");
+        let server_group = group.clone();
+        let (mut conn, _) = connect_scripted(|mut client| async move {
+            let (tag, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["query", "invite", "AbCdEf"]);
+            client.send_json(&tag, object!{
+                "status" => 200,
+                "id" => server_group.to_string(),
+                "subject" => "Book club",
+                "size" => 12,
+                "owner" => "447700900001@c.us"
+            }).await.unwrap();
+            let (tag, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["action", "invite", "AbCdEf"]);
+            client.send_json(&tag, object!{ "status" => 200, "gid" => server_group.to_string() }).await.unwrap();
+            let (tag, req) = client.recv_json().await.unwrap();
+            assert_eq!(req, array!["action", "inviteReset", server_group.to_string()]);
+            client.reply_status(&tag, 401).await.unwrap();
+            (client, ())
+        }).await;
+
+        conn.send(WaRequest::GetGroupInviteInfo("AbCdEf".into())).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupInviteInfo { code, info } => {
+                assert_eq!(code, "AbCdEf");
+                let info = info.unwrap();
+                assert_eq!(info.id, group);
+                assert_eq!(info.subject, "Book club");
+                assert_eq!(info.size, Some(12));
+                assert_eq!(info.owner, Some(jid("447700900001@c.us")));
+            },
+            _ => panic!("expected GroupInviteInfo")
+        }
+        conn.send(WaRequest::JoinGroup("AbCdEf".into())).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupJoined { jid, .. } => assert_eq!(jid.unwrap(), group),
+            _ => panic!("expected GroupJoined")
+        }
+        conn.send(WaRequest::ResetGroupInviteCode(group.clone())).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::GroupInviteCode { code: Err(WaError::StatusCode(401)), .. } => {},
+            _ => panic!("expected failed GroupInviteCode")
+        }
+    }
+    #[tokio::test]
+    async fn test_set_profile_picture() {
+        use crate::ProfilePicture;
+
+        let me = jid(TEST_JID);
+        let (mut conn, sent_rx) = connect_scripted(|mut client| async move {
+            let (tag, node) = client.recv_node().await.unwrap();
+            client.send_json(&tag, object!{ "status" => 200, "tag" => "1234" }).await.unwrap();
+            client.ack_node(401).await.unwrap();
+            let (tag, _) = client.recv_node().await.unwrap();
+            client.send_json(&tag, object!{ "tag" => "1234" }).await.unwrap();
+            (client, node)
+        }).await;
+
+        let pic = ProfilePicture { image: vec![1, 2, 3], preview: vec![4, 5] };
+        conn.send(WaRequest::SetProfilePicture { jid: me.clone(), image: Some(pic) }).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::PictureChange { jid, removed } => {
+                assert_eq!(jid, me);
+                assert!(!removed);
+            },
+            _ => panic!("expected PictureChange")
+        }
+        conn.send(WaRequest::SetProfilePicture { jid: me.clone(), image: None }).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::PictureChangeFail { status, .. } => assert_eq!(status, 401),
+            _ => panic!("expected PictureChangeFail")
+        }
+        // A reply with no status at all isn't taken as success.
+        conn.send(WaRequest::SetProfilePicture { jid: me.clone(), image: None }).await.unwrap();
+        loop {
+            match conn.next().await {
+                Some(Ok(WaEvent::PictureChange { .. })) => panic!("missing status taken as success"),
+                Some(Ok(_)) => {},
+                Some(Err(WaError::JsonFieldMissing("status"))) => break,
+                Some(Err(e)) => panic!("expected a missing status error, got {}", e),
+                None => panic!("connection ended")
+            }
+        }
+
+        let node = sent_rx.await.unwrap();
+        let picture = match node.content {
+            NodeContent::List(mut children) => children.remove(0),
+            _ => panic!("action node has no children")
+        };
+        assert_eq!(picture.desc, "picture");
+        assert_eq!(picture.attributes["type"].as_str(), "set");
+        match picture.content {
+            NodeContent::List(ref children) => {
+                assert_eq!(children[0].desc, "image");
+                assert_eq!(children[1].desc, "preview");
+                match children[1].content {
+                    NodeContent::Binary(ref b) => assert_eq!(b, &[4, 5]),
+                    _ => panic!("preview isn't binary")
+                }
             },
+            _ => panic!("picture node has no children")
         }
-        Ok(())
     }
 }
@@ -1,4 +1,12 @@
-//! Session management types.
+//! Session management types, and somewhere to keep them.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::crypto;
+use crate::errors::*;
 
 /// Stores persistent session data, used to login without scanning the QR code again.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -44,3 +52,165 @@ impl SessionState {
         }
     }
 }
+
+/// Somewhere to keep a `PersistentSession` between runs.
+///
+/// Give one of these to `ConnectionConfig::session_store()`, and the
+/// connection will save the session whenever one is established (including
+/// when the server hands out new tokens on a persistent login), so you
+/// don't have to remember to.
+pub trait SessionStore: Send + Sync {
+    /// Load the stored session, if there is one.
+    fn load(&self) -> WaResult<Option<PersistentSession>>;
+    /// Store the session, replacing any previously stored one.
+    fn save(&self, sess: &PersistentSession) -> WaResult<()>;
+    /// Delete the stored session, if there is one.
+    fn delete(&self) -> WaResult<()>;
+}
+impl fmt::Debug for dyn SessionStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionStore")
+    }
+}
+
+/// Write a file atomically-ish, by writing to a temporary file and renaming
+/// it over the original, so a crash halfway through doesn't lose the session.
+///
+/// On Unix, the file is only readable by its owner, since the session is as
+/// good as a password.
+fn replace_file(path: &PathBuf, data: &[u8]) -> WaResult<()> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    // A leftover temporary file might have looser permissions, which
+    // opening it again wouldn't change.
+    remove_file(&PathBuf::from(&tmp))?;
+    let mut opts = fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_file(path: &PathBuf) -> WaResult<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+fn remove_file(path: &PathBuf) -> WaResult<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Stores the session as plain JSON in a file.
+///
+/// Anyone who can read the file can use the session, so make sure it's
+/// somewhere safe (or use `EncryptedFileStore`).
+#[derive(Debug, Clone)]
+pub struct JsonFileStore {
+    path: PathBuf
+}
+impl JsonFileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+impl SessionStore for JsonFileStore {
+    fn load(&self) -> WaResult<Option<PersistentSession>> {
+        match read_file(&self.path)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None)
+        }
+    }
+    fn save(&self, sess: &PersistentSession) -> WaResult<()> {
+        replace_file(&self.path, &serde_json::to_vec_pretty(sess)?)
+    }
+    fn delete(&self) -> WaResult<()> {
+        remove_file(&self.path)
+    }
+}
+
+/// Stores the session in a file, encrypted with a passphrase.
+///
+/// The encryption keys are derived from the passphrase with PBKDF2, using
+/// a fresh random salt each time the session is saved.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    passphrase: String
+}
+impl EncryptedFileStore {
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(path: P, passphrase: S) -> Self {
+        Self {
+            path: path.into(),
+            passphrase: passphrase.into()
+        }
+    }
+}
+impl fmt::Debug for EncryptedFileStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedFileStore")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+impl SessionStore for EncryptedFileStore {
+    fn load(&self) -> WaResult<Option<PersistentSession>> {
+        let data = match read_file(&self.path)? {
+            Some(d) => d,
+            None => return Ok(None)
+        };
+        let plaintext = crypto::decrypt_with_passphrase(self.passphrase.as_bytes(), &data)
+            .map_err(|_| WaError::SessionDecrypt)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+    fn save(&self, sess: &PersistentSession) -> WaResult<()> {
+        let plaintext = serde_json::to_vec(sess)?;
+        replace_file(&self.path, &crypto::encrypt_with_passphrase(self.passphrase.as_bytes(), &plaintext))
+    }
+    fn delete(&self) -> WaResult<()> {
+        remove_file(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::config::ConnectionConfig;
+
+    #[tokio::test]
+    async fn test_session_store_saved() {
+        let path = std::env::temp_dir().join(format!("wa-test-session-{}.json", std::process::id()));
+        let store = JsonFileStore::new(&path);
+        store.delete().unwrap();
+        let config = ConnectionConfig::new()
+            .session_store(store.clone());
+        let _conn = connect_with(config, |client| client.hold_open()).await;
+        assert_eq!(store.load().unwrap(), Some(test_session()));
+        store.delete().unwrap();
+        assert_eq!(store.load().unwrap(), None);
+    }    #[cfg(unix)]
+    #[test]
+    fn test_session_file_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("wa-test-private-{}.json", std::process::id()));
+        let store = JsonFileStore::new(&path);
+        store.save(&test_session()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        store.delete().unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
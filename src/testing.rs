@@ -0,0 +1,598 @@
+//! A fake WhatsApp Web server, for testing without the real thing.
+//!
+//! `MockServer` listens on a local port, and hands out a `MockClient` for
+//! each `WebConnection` that connects to it (point the connection at the
+//! server using `MockServer::config()`). The `MockClient` can then walk the
+//! connection through the login handshake, and script whatever frames the
+//! test needs.
+//!
+//! ```rust,ignore
+//! let mut server = MockServer::bind().await?;
+//! let mut conn = WebConnection::connect_persistent_with(sess.clone(), server.config()).await?;
+//! let mut client = server.accept().await?;
+//! client.login_persistent(&sess, &jid, true).await?;
+//! client.send_node(testing::contacts(vec![contact])).await?;
+//! ```
+//!
+//! This module is only available with the `testing` feature.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use futures::{SinkExt, StreamExt};
+use futures::channel::oneshot;
+use json::JsonValue;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{self as ws, WebSocketStream};
+use ws::tungstenite::Message;
+
+use crate::config::ConnectionConfig;
+use crate::websocket_protocol::{WebsocketMessage, WebsocketMessagePayload};
+use crate::session::PersistentSession;
+use crate::message::{ChatMessage, MessageId, MessageAckLevel};
+use crate::node_wire::IntoCow;
+use crate::event::WaEvent;
+use crate::{crypto, Jid, Contact, Chat, WebConnection, ConnectionHandle};
+use crate::errors::*;
+
+pub use crate::node_wire::{Node, NodeContent};
+
+/// A fake WhatsApp Web server, listening on localhost.
+pub struct MockServer {
+    listener: TcpListener,
+    addr: SocketAddr
+}
+impl MockServer {
+    /// Start listening on a random local port.
+    pub async fn bind() -> WaResult<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        Ok(Self { listener, addr })
+    }
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    /// A `ConnectionConfig` that connects to this server.
+    pub fn config(&self) -> ConnectionConfig {
+        ConnectionConfig::new()
+            .endpoint(format!("ws://{}/ws", self.addr))
+    }
+    /// Wait for a client to connect.
+    pub async fn accept(&mut self) -> WaResult<MockClient> {
+        let (stream, _) = self.listener.accept().await?;
+        let ws = ws::accept_async(stream).await?;
+        Ok(MockClient {
+            ws,
+            client_id: None,
+            keys: None,
+            ref_ttl: 20000,
+            refs_issued: 0,
+            tag_counter: 0
+        })
+    }
+}
+
+/// The server's end of a single client connection.
+///
+/// Pings from the client are answered automatically, and never returned
+/// from the `recv` methods.
+pub struct MockClient {
+    ws: WebSocketStream<TcpStream>,
+    /// The (base64) client ID sent in the `init` request.
+    client_id: Option<String>,
+    /// The `enc` and `mac` keys, once a session has been established.
+    keys: Option<([u8; 32], [u8; 32])>,
+    /// How long pairing refs last for, in ms.
+    ref_ttl: u64,
+    refs_issued: u32,
+    tag_counter: u32
+}
+impl MockClient {
+    fn alloc_tag(&mut self) -> String {
+        self.tag_counter += 1;
+        format!("s{}", self.tag_counter)
+    }
+    /// Receive the next websocket message from the client.
+    pub async fn recv(&mut self) -> WaResult<Message> {
+        loop {
+            let msg = self.ws.next().await
+                .ok_or(WaError::WebsocketDisconnected)??;
+            match msg {
+                Message::Text(ref t) if t.starts_with("?,") => {
+                    let pong = format!("!{}", chrono::Utc::now().timestamp_millis());
+                    self.ws.send(Message::Text(pong)).await?;
+                },
+                Message::Ping(_) | Message::Pong(_) => {},
+                Message::Close(_) => return Err(WaError::WebsocketDisconnected),
+                m => return Ok(m)
+            }
+        }
+    }
+    /// Receive a JSON message from the client, returning its tag and payload.
+    pub async fn recv_json(&mut self) -> WaResult<(String, JsonValue)> {
+        let msg = self.recv().await?;
+        let msg = WebsocketMessage::deserialize(&msg)
+            .ok_or(WaError::Untyped("invalid websocket message from client"))?;
+        match msg.payload {
+            WebsocketMessagePayload::Json(j) => Ok((msg.tag.into_owned(), j)),
+            _ => Err(WaError::Untyped("expected a JSON message from client"))
+        }
+    }
+    /// Receive a binary message from the client, returning its tag and
+    /// decrypted node.
+    pub async fn recv_node(&mut self) -> WaResult<(String, Node)> {
+        let (enc, mac) = self.keys.ok_or(WaError::InvalidSessionState)?;
+        let msg = self.recv().await?;
+        let msg = WebsocketMessage::deserialize(&msg)
+            .ok_or(WaError::Untyped("invalid websocket message from client"))?;
+        match msg.payload {
+            WebsocketMessagePayload::BinarySimple(p) => {
+                // Skip over the metric and flag bytes.
+                if p.len() < 2 {
+                    bail_untyped!("binary message from client too short");
+                }
+                let dec = crypto::verify_and_decrypt_message(&enc, &mac, &p[2..])?;
+                Ok((msg.tag.into_owned(), Node::deserialize(&dec)?))
+            },
+            _ => Err(WaError::Untyped("expected a binary message from client"))
+        }
+    }
+    /// Send a JSON message with the given tag.
+    pub async fn send_json(&mut self, tag: &str, payload: JsonValue) -> WaResult<()> {
+        let msg = WebsocketMessage {
+            tag: tag.into(),
+            payload: WebsocketMessagePayload::Json(payload)
+        };
+        self.ws.send(msg.serialize()).await?;
+        Ok(())
+    }
+    /// Send an unsolicited JSON message (like `["Conn", ...]`).
+    pub async fn send_server_message(&mut self, payload: JsonValue) -> WaResult<()> {
+        let tag = self.alloc_tag();
+        self.send_json(&tag, payload).await
+    }
+    /// Reply to the message with the given tag with a bare status code.
+    pub async fn reply_status(&mut self, tag: &str, status: u16) -> WaResult<()> {
+        self.send_json(tag, object!{ "status" => status }).await
+    }
+    /// Encrypt and send a node with the given tag.
+    pub async fn send_node_tagged(&mut self, tag: &str, node: Node) -> WaResult<()> {
+        let (enc, mac) = self.keys.ok_or(WaError::InvalidSessionState)?;
+        let encrypted = crypto::sign_and_encrypt_message(&enc, &mac, &node.serialize());
+        let msg = WebsocketMessage {
+            tag: tag.into(),
+            payload: WebsocketMessagePayload::BinarySimple(&encrypted)
+        };
+        self.ws.send(msg.serialize()).await?;
+        Ok(())
+    }
+    /// Encrypt and send an unsolicited node.
+    pub async fn send_node(&mut self, node: Node) -> WaResult<()> {
+        let tag = self.alloc_tag();
+        self.send_node_tagged(&tag, node).await
+    }
+    /// Receive a node message, and reply to it with a bare status code.
+    pub async fn ack_node(&mut self, status: u16) -> WaResult<Node> {
+        let (tag, node) = self.recv_node().await?;
+        self.reply_status(&tag, status).await?;
+        Ok(node)
+    }
+    /// Keep the connection open (without reading from it) for good.
+    pub async fn hold_open(self) {
+        futures::future::pending::<()>().await
+    }
+    /// Close the connection.
+    pub async fn close(mut self) -> WaResult<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+    /// Set how long the pairing refs we hand out are valid for.
+    pub fn set_ref_ttl(&mut self, ttl: std::time::Duration) {
+        self.ref_ttl = ttl.as_millis() as u64;
+    }
+    /// Handle the client's `init` request, replying with a pairing ref.
+    pub async fn accept_init(&mut self) -> WaResult<()> {
+        let (tag, j) = self.recv_json().await?;
+        if j[0] != "admin" || j[1] != "init" {
+            bail_untyped!("expected init request, got {}", j);
+        }
+        self.client_id = Some(j[4].as_str().ok_or("init request without client id")?.to_owned());
+        self.refs_issued += 1;
+        self.send_json(&tag, object!{
+            "status" => 200,
+            "ref" => format!("{}@mock-ref", self.refs_issued),
+            "ttl" => self.ref_ttl,
+            "update" => false,
+            "curr" => "0.4.1307",
+            "time" => chrono::Utc::now().timestamp_millis()
+        }).await
+    }
+    /// Handle the client's request for a new pairing ref, after the
+    /// previous one expired.
+    pub async fn accept_reref(&mut self) -> WaResult<()> {
+        let (tag, j) = self.recv_json().await?;
+        if j[0] != "admin" || j[1] != "Conn" || j[2] != "reref" {
+            bail_untyped!("expected reref request, got {}", j);
+        }
+        self.refs_issued += 1;
+        self.send_json(&tag, object!{
+            "status" => 200,
+            "ref" => format!("{}@mock-ref", self.refs_issued),
+            "ttl" => self.ref_ttl
+        }).await
+    }
+    /// Complete a new login, as if the phone had scanned the QR code with
+    /// the given pairing string (from `WaEvent::ScanCode`).
+    ///
+    /// Call `accept_init()` first. Returns the session the client should
+    /// end up with.
+    pub async fn complete_pairing(&mut self, pairing: &str, jid: &Jid) -> WaResult<PersistentSession> {
+        let mut parts = pairing.split(',');
+        let _ = parts.next();
+        let public_key = base64::decode(parts.next().ok_or("pairing string missing public key")?)?;
+        let client_id = base64::decode(parts.next().ok_or("pairing string missing client id")?)?;
+        if Some(base64::encode(&client_id)) != self.client_id {
+            bail_untyped!("pairing string client id doesn't match init request");
+        }
+
+        let mut enc = [0u8; 32];
+        let mut mac = [0u8; 32];
+        let rng = SystemRandom::new();
+        rng.fill(&mut enc)?;
+        rng.fill(&mut mac)?;
+        let secret = crypto::build_secret(&public_key, &enc, &mac)?;
+
+        let mut cid = [0u8; 8];
+        if client_id.len() != cid.len() {
+            bail_untyped!("client id has the wrong length");
+        }
+        cid.copy_from_slice(&client_id);
+        let sess = PersistentSession {
+            client_token: "mock-client-token".into(),
+            server_token: "mock-server-token".into(),
+            client_id: cid,
+            enc,
+            mac
+        };
+        self.send_server_message(array!["Conn", object!{
+            "wid" => jid.to_string(),
+            "clientToken" => sess.client_token.clone(),
+            "serverToken" => sess.server_token.clone(),
+            "secret" => base64::encode(&secret),
+            "pushname" => "mock",
+            "platform" => "android"
+        }]).await?;
+        self.keys = Some((enc, mac));
+        Ok(sess)
+    }
+    /// Handle a persistent login (`init`, then `takeover`) for the given
+    /// session, optionally making the client answer a challenge first.
+    pub async fn login_persistent(&mut self, sess: &PersistentSession, jid: &Jid, challenge: bool) -> WaResult<()> {
+        self.accept_init().await?;
+        let (tag, j) = self.recv_json().await?;
+        if j[0] != "admin" || j[1] != "login" || j[5] != "takeover" {
+            bail_untyped!("expected takeover request, got {}", j);
+        }
+        if j[2] != sess.client_token.as_str() || j[3] != sess.server_token.as_str() {
+            self.reply_status(&tag, 401).await?;
+            bail_untyped!("client sent wrong tokens in takeover");
+        }
+        if challenge {
+            let mut data = [0u8; 32];
+            SystemRandom::new().fill(&mut data)?;
+            self.send_server_message(array!["Cmd", object!{
+                "type" => "challenge",
+                "challenge" => base64::encode(&data)
+            }]).await?;
+            let (ctag, cj) = self.recv_json().await?;
+            if cj[0] != "admin" || cj[1] != "challenge" {
+                bail_untyped!("expected challenge response, got {}", cj);
+            }
+            let expected = crypto::sign_challenge(&sess.mac, &data);
+            if cj[2] != base64::encode(expected.as_ref()).as_str() {
+                self.reply_status(&ctag, 401).await?;
+                bail_untyped!("client sent wrong challenge response");
+            }
+            self.reply_status(&ctag, 200).await?;
+        }
+        self.reply_status(&tag, 200).await?;
+        self.send_server_message(array!["Conn", object!{
+            "wid" => jid.to_string(),
+            "clientToken" => sess.client_token.clone(),
+            "serverToken" => sess.server_token.clone(),
+            "pushname" => "mock",
+            "platform" => "android"
+        }]).await?;
+        self.keys = Some((sess.enc, sess.mac));
+        Ok(())
+    }
+}
+
+/// Build the initial contacts node the phone sends after login.
+pub fn contacts(contacts: Vec<Contact>) -> Node {
+    let list = contacts.into_iter()
+        .map(|c| {
+            let mut node = Node::new_empty("user");
+            node.set_attribute("jid", NodeContent::Jid(c.jid));
+            if let Some(name) = c.name {
+                node.set_attribute("name", NodeContent::String(name.cow()));
+            }
+            if let Some(notify) = c.notify {
+                node.set_attribute("notify", NodeContent::String(notify.cow()));
+            }
+            node
+        })
+        .collect();
+    let mut attributes = HashMap::new();
+    attributes.insert("type".cow(), NodeContent::Token("contacts"));
+    Node::new("response", attributes, NodeContent::List(list))
+}
+
+/// Build the initial chat list node the phone sends after login.
+pub fn chats(chats: Vec<Chat>) -> Node {
+    let list = chats.into_iter()
+        .map(|c| {
+            let mut node = Node::new_empty("chat");
+            node.set_attribute("jid", NodeContent::Jid(c.jid));
+            node.set_attribute("t", NodeContent::String(c.last_activity.to_string().cow()));
+            if let Some(name) = c.name {
+                node.set_attribute("name", NodeContent::String(name.cow()));
+            }
+            if let Some(mute) = c.mute_until {
+                node.set_attribute("mute", NodeContent::String(mute.to_string().cow()));
+            }
+            if let Some(pin) = c.pin_time {
+                node.set_attribute("pin", NodeContent::String(pin.to_string().cow()));
+            }
+            node.set_attribute("spam", NodeContent::String(c.spam.to_string().cow()));
+            node.set_attribute("read_only", NodeContent::String(c.read_only.to_string().cow()));
+            node
+        })
+        .collect();
+    let mut attributes = HashMap::new();
+    attributes.insert("type".cow(), NodeContent::Token("chat"));
+    Node::new("response", attributes, NodeContent::List(list))
+}
+
+/// Build an `action` node containing the given child nodes.
+///
+/// `add` is usually `"relay"` for new events, or `"last"` / `"before"`
+/// for backlog.
+pub fn action(add: &'static str, children: Vec<Node>) -> Node {
+    let mut attributes = HashMap::new();
+    attributes.insert("add".cow(), NodeContent::String(add.cow()));
+    Node::new("action", attributes, NodeContent::List(children))
+}
+
+/// Build a `message` node, for use with `action()`.
+///
+/// Unlike with `WaRequest::SendMessage`, the message can be in either
+/// direction.
+pub fn message(msg: ChatMessage) -> Node {
+    use protobuf::Message;
+
+    let webmessage = msg.into_proto().expect("failed to convert message");
+    let content = webmessage.write_to_bytes().expect("failed to serialize message");
+    Node::new("message", HashMap::new(), NodeContent::Binary(content))
+}
+
+/// Build the response to a message history query.
+pub fn history(msgs: Vec<ChatMessage>) -> Node {
+    let mut attributes = HashMap::new();
+    attributes.insert("type".cow(), NodeContent::Token("message"));
+    let content = if msgs.is_empty() {
+        NodeContent::None
+    }
+    else {
+        NodeContent::List(msgs.into_iter().map(message).collect())
+    };
+    Node::new("response", attributes, content)
+}
+
+/// Build a `received` (message ack) node, for use with `action()`.
+pub fn message_ack(id: &MessageId, jid: Jid, level: MessageAckLevel, owner: bool) -> Node {
+    let typ = match level {
+        MessageAckLevel::Received => "message",
+        MessageAckLevel::Read => "read",
+        MessageAckLevel::Played => "played",
+        _ => "error"
+    };
+    let mut node = Node::new_empty("received");
+    node.set_attribute("index", NodeContent::String(id.0.clone().cow()));
+    node.set_attribute("type", NodeContent::String(typ.cow()));
+    node.set_attribute("jid", NodeContent::Jid(jid));
+    node.set_attribute("owner", NodeContent::String(owner.to_string().cow()));
+    node
+}
+
+/// Build a `battery` node, for use with `action()`.
+pub fn battery(level: u8) -> Node {
+    let mut node = Node::new_empty("battery");
+    node.set_attribute("value", NodeContent::String(level.to_string().cow()));
+    node
+}
+
+/// Parse a JID, panicking if it's invalid. Handy in tests.
+pub fn jid(s: &str) -> Jid {
+    Jid::from_str(s).expect("invalid jid")
+}
+
+/// The JID that `connect_with()` logs in as.
+pub const TEST_JID: &str = "447700900000@c.us";
+
+/// A made-up persistent session, for logging in to a `MockServer`.
+pub fn test_session() -> PersistentSession {
+    PersistentSession {
+        client_token: "client".into(),
+        server_token: "server".into(),
+        client_id: [1; 8],
+        enc: [2; 32],
+        mac: [3; 32]
+    }
+}
+
+/// Start a `MockServer`, and log in to it as `TEST_JID` with
+/// `test_session()`, then hand the mock client over to `serve` to do
+/// whatever it likes.
+///
+/// Panics if the login fails.
+pub async fn connect_with<F, Fut>(config: ConnectionConfig, serve: F) -> WebConnection
+    where F: FnOnce(MockClient) -> Fut + Send + 'static,
+          Fut: std::future::Future<Output=()> + Send + 'static {
+    let sess = test_session();
+    let mut server = MockServer::bind().await.unwrap();
+    let config = ConnectionConfig { endpoint: server.config().endpoint, ..config };
+    let server_sess = sess.clone();
+    tokio::spawn(async move {
+        let mut client = server.accept().await.unwrap();
+        client.login_persistent(&server_sess, &jid(TEST_JID), false).await.unwrap();
+        serve(client).await;
+    });
+    let mut conn = WebConnection::connect_persistent_with(sess, config).await.unwrap();
+    loop {
+        if let WaEvent::SessionEstablished { .. } = next_event(&mut conn).await {
+            return conn;
+        }
+    }
+}
+
+/// Log in like `connect_with()` (with the default config), then run
+/// `script` against the mock client to answer the test's requests.
+///
+/// Once the script is done, the connection is held open, and whatever it
+/// returned alongside the client (usually the requests it saw) arrives on
+/// the returned receiver.
+pub async fn connect_scripted<F, Fut, T>(script: F) -> (WebConnection, oneshot::Receiver<T>)
+    where F: FnOnce(MockClient) -> Fut + Send + 'static,
+          Fut: std::future::Future<Output=(MockClient, T)> + Send + 'static,
+          T: Send + 'static {
+    let (tx, rx) = oneshot::channel();
+    let conn = connect_with(ConnectionConfig::new(), |client| async move {
+        let (client, ret) = script(client).await;
+        let _ = tx.send(ret);
+        client.hold_open().await
+    }).await;
+    (conn, rx)
+}
+
+/// Get the next event from a connection, skipping over keepalive and
+/// state change noise.
+///
+/// Panics if the connection fails or ends.
+pub async fn next_event(conn: &mut WebConnection) -> WaEvent {
+    loop {
+        match conn.next().await.expect("connection ended").expect("connection failed") {
+            WaEvent::Latency(_) | WaEvent::StateChanged(_) => {},
+            evt => return evt
+        }
+    }
+}
+
+/// Poll a connection in the background (throwing away its events), and
+/// return a handle to make requests with.
+pub fn spawn_handle(mut conn: WebConnection) -> ConnectionHandle {
+    let handle = conn.handle();
+    tokio::spawn(async move {
+        while let Some(Ok(_)) = conn.next().await {}
+    });
+    handle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ChatMessageContent, Direction, Peer};
+
+    #[tokio::test]
+    async fn test_new_login() {
+        let me = jid("447700900000@c.us");
+        let mut server = MockServer::bind().await.unwrap();
+        let config = server.config();
+        let (pairing_tx, pairing_rx) = oneshot::channel::<String>();
+        let server_jid = me.clone();
+        let srv = tokio::spawn(async move {
+            let mut client = server.accept().await.unwrap();
+            client.accept_init().await.unwrap();
+            let pairing = pairing_rx.await.unwrap();
+            let sess = client.complete_pairing(&pairing, &server_jid).await.unwrap();
+            (sess, client)
+        });
+
+        let mut conn = WebConnection::connect_new_with(config).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::WebsocketConnected => {},
+            _ => panic!("expected WebsocketConnected")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::ScanCode { pairing, .. } => pairing_tx.send(pairing).unwrap(),
+            _ => panic!("expected ScanCode")
+        }
+        let (expected, _client) = srv.await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::SessionEstablished { persistent, jid } => {
+                assert_eq!(persistent, expected);
+                assert_eq!(jid, me);
+            },
+            _ => panic!("expected SessionEstablished")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persistent_login_and_nodes() {
+        let me = jid("447700900000@c.us");
+        let them = jid("447700900001@c.us");
+        let sess = test_session();
+        let mut server = MockServer::bind().await.unwrap();
+        let config = server.config();
+        let (server_sess, server_me, server_them) = (sess.clone(), me.clone(), them.clone());
+        let srv = tokio::spawn(async move {
+            let mut client = server.accept().await.unwrap();
+            client.login_persistent(&server_sess, &server_me, true).await.unwrap();
+            client.send_node(contacts(vec![Contact {
+                name: Some("Them".into()),
+                notify: None,
+                jid: server_them.clone()
+            }])).await.unwrap();
+            let mut msg = ChatMessage::new(server_me, ChatMessageContent::Text("hello".into()));
+            msg.direction = Direction::Receiving(Peer::Individual(server_them));
+            client.send_node(action("relay", vec![message(msg), battery(42)])).await.unwrap();
+            client
+        });
+
+        let mut conn = WebConnection::connect_persistent_with(sess.clone(), config).await.unwrap();
+        match next_event(&mut conn).await {
+            WaEvent::WebsocketConnected => {},
+            _ => panic!("expected WebsocketConnected")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::SessionEstablished { persistent, jid } => {
+                assert_eq!(persistent, sess);
+                assert_eq!(jid, me);
+            },
+            _ => panic!("expected SessionEstablished")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::InitialContacts(c) => {
+                assert_eq!(c.len(), 1);
+                assert_eq!(c[0].jid, them);
+            },
+            _ => panic!("expected InitialContacts")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::Message { msg, .. } => {
+                match msg.content {
+                    ChatMessageContent::Text(t) => assert_eq!(t, "hello"),
+                    _ => panic!("wrong message content")
+                }
+            },
+            _ => panic!("expected Message")
+        }
+        match next_event(&mut conn).await {
+            WaEvent::BatteryLevel(l) => assert_eq!(l, 42),
+            _ => panic!("expected BatteryLevel")
+        }
+        let _client = srv.await.unwrap();
+    }
+}
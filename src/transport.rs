@@ -0,0 +1,146 @@
+//! Getting a byte stream to run the websocket over.
+//!
+//! Normally, this means a TCP connection to the endpoint (perhaps via a
+//! proxy), wrapped in TLS. Anything implementing `Transport` can be used
+//! instead, though.
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::{ConnectionConfig, Proxy};
+use crate::errors::*;
+
+/// A byte stream that a websocket connection can run over.
+///
+/// This is implemented for anything that's `AsyncRead + AsyncWrite`,
+/// so you shouldn't need to implement it yourself.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+pub(crate) type BoxedTransport = Box<dyn Transport>;
+
+/// Get the host and port the websocket request should go to.
+fn target_of(uri: &http::Uri) -> Result<(String, u16)> {
+    let host = uri.host()
+        .ok_or_else(|| WaError::InvalidConfig("endpoint has no host".into()))?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(p), _) => p,
+        (None, Some("wss")) => 443,
+        (None, Some("ws")) => 80,
+        _ => return Err(WaError::InvalidConfig("endpoint must be a ws:// or wss:// URL".into()))
+    };
+    Ok((host.to_owned(), port))
+}
+
+/// Open a TCP connection to the endpoint, going via a proxy if configured.
+pub(crate) async fn dial(config: &ConnectionConfig, uri: &http::Uri) -> Result<BoxedTransport> {
+    let (host, port) = target_of(uri)?;
+    let stream = match config.proxy {
+        None => TcpStream::connect((&host as &str, port)).await?,
+        Some(Proxy::Http { ref addr, ref auth }) => {
+            let mut stream = TcpStream::connect(addr as &str).await?;
+            http_connect(&mut stream, &host, port, auth.as_ref()).await?;
+            stream
+        },
+        Some(Proxy::Socks5 { ref addr, ref auth }) => {
+            let mut stream = TcpStream::connect(addr as &str).await?;
+            socks5_connect(&mut stream, &host, port, auth.as_ref()).await?;
+            stream
+        }
+    };
+    Ok(Box::new(stream))
+}
+
+/// Wrap the transport in TLS, if the endpoint requires it.
+pub(crate) async fn wrap_tls(config: &ConnectionConfig, uri: &http::Uri, stream: BoxedTransport) -> Result<BoxedTransport> {
+    if uri.scheme_str() != Some("wss") {
+        return Ok(stream);
+    }
+    let (host, _) = target_of(uri)?;
+    let mut builder = native_tls::TlsConnector::builder();
+    for pem in config.tls.root_certificates.iter() {
+        builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+    }
+    builder.disable_built_in_roots(config.tls.disable_built_in_roots);
+    builder.danger_accept_invalid_certs(config.tls.accept_invalid_certs);
+    builder.danger_accept_invalid_hostnames(config.tls.accept_invalid_hostnames);
+    let connector = tokio_tls::TlsConnector::from(builder.build()?);
+    let stream = connector.connect(&host, stream).await?;
+    Ok(Box::new(stream))
+}
+
+async fn http_connect(stream: &mut TcpStream, host: &str, port: u16, auth: Option<&(String, String)>) -> Result<()> {
+    let mut req = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some((user, pass)) = auth {
+        let creds = base64::encode(&format!("{}:{}", user, pass));
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", creds));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // Read the response a byte at a time, so we don't eat anything
+    // the server sends after the headers.
+    let mut resp = Vec::new();
+    while !resp.ends_with(b"\r\n\r\n") {
+        if resp.len() > 8192 {
+            return Err(WaError::Proxy("HTTP proxy response too long".into()));
+        }
+        resp.push(stream.read_u8().await?);
+    }
+    let resp = String::from_utf8_lossy(&resp);
+    let status = resp.split_whitespace().nth(1);
+    if status != Some("200") {
+        let line = resp.lines().next().unwrap_or("");
+        return Err(WaError::Proxy(format!("HTTP proxy refused CONNECT: {}", line)));
+    }
+    Ok(())
+}
+
+async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16, auth: Option<&(String, String)>) -> Result<()> {
+    // Greeting: offer either no authentication, or username/password.
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err(WaError::Proxy("SOCKS5 proxy rejected authentication method".into()));
+    }
+    if let Some((user, pass)) = auth {
+        if user.len() > 255 || pass.len() > 255 {
+            return Err(WaError::Proxy("SOCKS5 username or password too long".into()));
+        }
+        let mut req = vec![0x01, user.len() as u8];
+        req.extend(user.as_bytes());
+        req.push(pass.len() as u8);
+        req.extend(pass.as_bytes());
+        stream.write_all(&req).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(WaError::Proxy("SOCKS5 authentication failed".into()));
+        }
+    }
+    if host.len() > 255 {
+        return Err(WaError::Proxy("hostname too long for SOCKS5".into()));
+    }
+    // CONNECT, with the hostname resolved by the proxy.
+    let mut req = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    req.extend(host.as_bytes());
+    req.extend(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        return Err(WaError::Proxy(format!("SOCKS5 proxy refused CONNECT (code {})", head[1])));
+    }
+    // Skip over the bound address, which we don't care about.
+    let addr_len = match head[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        x => return Err(WaError::Proxy(format!("invalid SOCKS5 address type {}", x)))
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut rest).await?;
+    Ok(())
+}